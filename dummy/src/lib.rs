@@ -1,13 +1,10 @@
-use near_account::{AccountDeposits, Accounts, NearAccounts, NewInfo};
-use near_internal_balances_plugin::impl_near_balance_plugin;
+use near_account::{Accounts, NearAccounts, NewInfo};
+use near_internal_balances_plugin::{impl_near_balance_plugin, InternalBalanceReceiver};
 
-use near_contract_standards::storage_management::StorageManagement;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::{LazyOption, UnorderedMap};
+use near_sdk::collections::UnorderedMap;
 use near_sdk::json_types::{ValidAccountId, U128};
-use near_sdk::{
-    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, PanicOnDefault, PromiseOrValue,
-};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, PanicOnDefault, PromiseOrValue};
 
 near_sdk::setup_alloc!();
 
@@ -15,6 +12,7 @@ near_sdk::setup_alloc!();
 pub struct AccountInfo {
     pub message: String,
     pub internal_balance: UnorderedMap<AccountId, Balance>,
+    pub allowances: UnorderedMap<(AccountId, AccountId), Balance>,
 }
 
 impl NewInfo for AccountInfo {
@@ -22,6 +20,7 @@ impl NewInfo for AccountInfo {
         Self {
             message: "".to_string(),
             internal_balance: UnorderedMap::new(format!("{}-bal", account_id).as_bytes()),
+            allowances: UnorderedMap::new(format!("{}-allow", account_id).as_bytes()),
         }
     }
 }
@@ -32,7 +31,7 @@ pub struct Contract {
     pub accounts: Accounts<AccountInfo>,
 }
 
-impl_near_balance_plugin!(Contract, accounts, AccountInfo, internal_balance);
+impl_near_balance_plugin!(Contract, accounts, AccountInfo, internal_balance, allowances);
 
 #[near_bindgen]
 impl Contract {
@@ -40,12 +39,14 @@ impl Contract {
     /// the given fungible token metadata.
     #[init]
     pub fn new() -> Self {
-        Contract { accounts: Accounts::new() }
+        let mut accounts = Accounts::new();
+        accounts.storage_from_attached_deposit = true;
+        Contract { accounts }
     }
 
+    /// Set the caller's message, the attached deposit pays for its storage and the rest is refunded
     #[payable]
     pub fn write_message(&mut self, set_own_message: String) {
-        assert_one_yocto();
         let caller = env::predecessor_account_id();
         let account = &mut self.accounts.get_account_checked(&caller);
         account.info.message = set_own_message;
@@ -57,3 +58,52 @@ impl Contract {
         account.map(|a| a.info.message).unwrap_or("".to_string())
     }
 }
+
+#[near_bindgen]
+impl InternalBalanceReceiver for Contract {
+    /// Keeps the transferred balance except for the amount given in `msg`
+    fn on_internal_balance_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        token_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        log!("Received {} of {} from {}", amount.0, token_id, sender_id);
+        let unused = msg.parse::<u128>().unwrap_or(0);
+        PromiseOrValue::Value(U128(unused.min(amount.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    use super::*;
+
+    #[test]
+    fn test_write_message_paid_with_attached_deposit() {
+        let mut context = VMContextBuilder::new();
+        context.current_account_id(accounts(0)).account_balance(10u128.pow(30));
+        testing_env!(context.build());
+        let mut contract = Contract::new();
+        let min = contract.accounts_storage_balance_bounds().min.0;
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(min).build());
+        contract.accounts_storage_deposit(None, Some(true));
+        let account_id: AccountId = accounts(1).into();
+        let prior = contract.accounts.get_account_checked(&account_id);
+
+        // The registration alone cannot pay for the message
+        let message = "a".repeat(1000);
+        let storage_cost = 1000 * env::storage_byte_cost();
+        testing_env!(context.attached_deposit(storage_cost * 2).build());
+        contract.write_message(message.clone());
+
+        assert_eq!(contract.get_message(accounts(1)), message);
+        let account = contract.accounts.get_account_checked(&account_id);
+        assert_eq!(account.near_used_for_storage, prior.near_used_for_storage + storage_cost);
+        // Only the storage cost is kept, the rest of the deposit is refunded
+        assert_eq!(account.near_amount, prior.near_amount + storage_cost);
+    }
+}
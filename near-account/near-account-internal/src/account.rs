@@ -6,9 +6,19 @@ use near_sdk::{
     AccountId, Balance, Promise, StorageUsage,
 };
 
-use crate::{Accounts, NewInfo};
+use crate::{Accounts, AccountsError, AccountsResult, NewInfo, UnwrapOrPanic};
 
-pub trait AccountInfoTrait: BorshSerialize + BorshDeserialize + NewInfo {}
+pub trait AccountInfoTrait: BorshSerialize + BorshDeserialize + NewInfo {
+    /// Whether the account can be unregistered without forcing, i.e. it holds no balances
+    fn can_unregister(&self) -> bool {
+        true
+    }
+
+    /// Clear any nested state (i.e. collections) held by the info before the account is removed
+    ///
+    /// `accounts` gives access to the state kept next to the accounts, the account is already removed from it
+    fn on_unregister(&mut self, _accounts: &mut Accounts<Self>) {}
+}
 
 /// Account information and storage cost.
 #[derive(BorshSerialize, BorshDeserialize, Default, Debug)]
@@ -16,6 +26,16 @@ pub struct Account<Info: AccountInfoTrait> {
     /// Native NEAR amount sent to the contract
     pub near_amount: Balance,
     pub near_used_for_storage: Balance,
+    /// The number of bytes paid for by the account, including nested collections in `info`
+    pub storage_usage: StorageUsage,
+    /// The account which paid for some of the account's Near, if any
+    pub sponsor_id: Option<AccountId>,
+    /// The Near deposited by the sponsor, which is returned to the sponsor rather than the account
+    pub sponsored_amount: Balance,
+    /// Whether the sponsor only paid for storage as the sender of transfers, see `StoragePayer::Sponsor`
+    pub sponsor_is_storage_payer: bool,
+    /// The Near lent by the contract's storage reserve, which is repaid on the next deposit
+    pub storage_debt: Balance,
     pub info: Info,
 }
 
@@ -24,17 +44,95 @@ impl<Info: AccountInfoTrait> NewInfo for Account<Info> {
         Self {
             near_amount: 0,
             near_used_for_storage: 0,
+            storage_usage: 0,
+            sponsor_id: None,
+            sponsored_amount: 0,
+            sponsor_is_storage_payer: false,
+            storage_debt: 0,
             info: Info::default_from_account_id(account_id),
         }
     }
 }
 
+/// Sponsorship, where a third party pays for an account's Near
+///
+/// Storage is paid for with the sponsored Near first, so the sponsor is only returned what is not used for storage
+impl<Info: AccountInfoTrait> Account<Info> {
+    /// Check if the account has no sponsor or is already sponsored by `sponsor_id`
+    pub fn can_be_sponsored_by(&self, sponsor_id: &AccountId) -> bool {
+        self.sponsor_id.as_ref().map(|current| current == sponsor_id).unwrap_or(true)
+    }
+
+    /// Record `amount` of the account's Near as deposited by `sponsor_id`
+    pub fn add_sponsored(&mut self, sponsor_id: &AccountId, amount: Balance) {
+        self.try_add_sponsored(sponsor_id, amount).unwrap_or_panic()
+    }
+
+    /// Same as `add_sponsored` but returns an error if the account has another sponsor
+    pub fn try_add_sponsored(
+        &mut self,
+        sponsor_id: &AccountId,
+        amount: Balance,
+    ) -> AccountsResult<()> {
+        if !self.can_be_sponsored_by(sponsor_id) {
+            return Err(AccountsError::AlreadySponsored {
+                sponsor_id: self.sponsor_id.clone().unwrap(),
+            });
+        }
+        self.sponsor_id = Some(sponsor_id.clone());
+        self.sponsored_amount += amount;
+        Ok(())
+    }
+
+    /// Remove `amount` from the sponsored Near, clearing the sponsor once nothing is left
+    pub fn remove_sponsored(&mut self, amount: Balance) {
+        self.sponsored_amount = self.sponsored_amount.saturating_sub(amount);
+        if self.sponsored_amount == 0 {
+            self.sponsor_id = None;
+            self.sponsor_is_storage_payer = false;
+        }
+    }
+
+    /// Get the sponsored Near which is not used for storage
+    pub fn get_available_sponsored_near(&self) -> Balance {
+        self.sponsored_amount
+            .saturating_sub(self.near_used_for_storage)
+            .min(self.get_available_near())
+    }
+
+    /// Get the Near deposited by the account itself which is not used for storage
+    pub fn get_available_user_near(&self) -> Balance {
+        self.get_available_near() - self.get_available_sponsored_near()
+    }
+}
+
+impl<Info: AccountInfoTrait> Account<Info> {
+    /// Take `amount` of the account's free Near, i.e. to pay for another account's storage
+    pub(crate) fn charge_near(&mut self, amount: Balance) -> AccountsResult<()> {
+        let available = self.get_available_near();
+        if available < amount {
+            return Err(AccountsError::InsufficientNear { required: amount, available });
+        }
+        self.near_amount -= amount;
+        Ok(())
+    }
+}
+
 pub trait AccountDeposits<Info: AccountInfoTrait> {
     /// Check that storage is paid for and call the closure function
     fn check_storage<F, T: Sized>(&mut self, accounts: &mut Accounts<Info>, closure: F) -> T
     where
         F: FnOnce(&mut Accounts<Info>, &mut Self) -> T;
 
+    /// Same as `check_storage` but returns an error if the account cannot pay for the storage
+    fn try_check_storage<F, T: Sized>(
+        &mut self,
+        accounts: &mut Accounts<Info>,
+        closure: F,
+    ) -> AccountsResult<T>
+    where
+        F: FnOnce(&mut Accounts<Info>, &mut Self) -> T;
+
     fn get_available_near(&self) -> Balance;
 
     fn storage_balance(&self) -> StorageBalance;
@@ -48,6 +146,17 @@ pub trait AccountDeposits<Info: AccountInfoTrait> {
 
 impl<Info: AccountInfoTrait> AccountDeposits<Info> for Account<Info> {
     fn check_storage<F, T: Sized>(&mut self, accounts: &mut Accounts<Info>, closure: F) -> T
+    where
+        F: FnOnce(&mut Accounts<Info>, &mut Self) -> T,
+    {
+        self.try_check_storage(accounts, closure).unwrap_or_panic()
+    }
+
+    fn try_check_storage<F, T: Sized>(
+        &mut self,
+        accounts: &mut Accounts<Info>,
+        closure: F,
+    ) -> AccountsResult<T>
     where
         F: FnOnce(&mut Accounts<Info>, &mut Self) -> T,
     {
@@ -56,23 +165,8 @@ impl<Info: AccountInfoTrait> AccountDeposits<Info> for Account<Info> {
         let ret = closure(accounts, self);
 
         let storage_end = env::storage_usage();
-
-        if storage_end == storage_start {
-            ret
-        } else if storage_end > storage_start {
-            let storage_cost = (storage_end - storage_start) as u128 * env::storage_byte_cost();
-            let free_near = self.get_available_near();
-            if free_near < storage_cost {
-                panic!("Not enough Near to cover the transaction");
-            }
-            self.near_used_for_storage += storage_cost;
-            ret
-        } else {
-            let storage_refund = (storage_start - storage_end) as u128 * env::storage_byte_cost();
-            self.near_used_for_storage =
-                self.near_used_for_storage.checked_sub(storage_refund).unwrap_or(0);
-            ret
-        }
+        accounts.try_charge_storage(self, storage_start, storage_end)?;
+        Ok(ret)
     }
 
     fn storage_balance(&self) -> StorageBalance {
@@ -98,7 +192,8 @@ impl<Info: AccountInfoTrait> AccountDeposits<Info> for Account<Info> {
         let withdraw_amount = if amount.is_some() {
             let amount = amount.unwrap();
             if free < amount {
-                panic!("Cannot withdraw more than {} near", free);
+                Err(AccountsError::InsufficientNear { required: amount, available: free })
+                    .unwrap_or_panic()
             }
             amount
         } else {
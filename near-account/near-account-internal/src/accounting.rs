@@ -0,0 +1,40 @@
+use near_sdk::{
+    env,
+    json_types::{U128, U64},
+    serde::{Deserialize, Serialize},
+    AccountId,
+};
+
+use crate::{Account, AccountInfoTrait, Accounts};
+
+/// The storage footprint of an account, used to audit `near_used_for_storage`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountStorageUsage {
+    /// The number of bytes paid for by the account, including nested collections
+    pub storage_usage: U64,
+    /// The Near currently held for the account's storage
+    pub near_used_for_storage: U128,
+    /// The Near which the account's storage costs at the current byte cost
+    pub storage_cost: U128,
+    /// The Near lent to the account by the contract's storage reserve
+    pub storage_debt: U128,
+}
+
+impl<Info: AccountInfoTrait> Account<Info> {
+    pub fn account_storage_usage(&self) -> AccountStorageUsage {
+        AccountStorageUsage {
+            storage_usage: U64::from(self.storage_usage),
+            near_used_for_storage: U128::from(self.near_used_for_storage),
+            storage_cost: U128::from(self.storage_usage as u128 * env::storage_byte_cost()),
+            storage_debt: U128::from(self.storage_debt),
+        }
+    }
+}
+
+impl<Info: AccountInfoTrait> Accounts<Info> {
+    /// Get the storage footprint of an account, or None if the account is not registered
+    pub fn storage_usage_of(&self, account_id: &AccountId) -> Option<AccountStorageUsage> {
+        self.accounts.get(account_id).map(|account| account.account_storage_usage())
+    }
+}
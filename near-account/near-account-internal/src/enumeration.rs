@@ -0,0 +1,43 @@
+use near_contract_standards::storage_management::StorageBalance;
+use near_sdk::{serde::Serialize, AccountId};
+
+use crate::{AccountDeposits, AccountInfoTrait, Accounts};
+
+/// The maximum number of accounts returned by `accounts_list`, so that views stay within the gas limit
+pub const MAX_ACCOUNTS_PAGE_SIZE: u64 = 100;
+
+/// A registered account and its storage balance
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountStorageBalance {
+    pub account_id: AccountId,
+    pub storage_balance: StorageBalance,
+}
+
+impl<Info: AccountInfoTrait> Accounts<Info> {
+    /// Get the number of registered accounts
+    pub fn accounts_count(&self) -> u64 {
+        self.accounts.len()
+    }
+
+    /// List the registered accounts starting at `from_index`
+    ///
+    /// At most `limit` accounts are returned, capped at `MAX_ACCOUNTS_PAGE_SIZE`
+    pub fn accounts_list(
+        &self,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<AccountStorageBalance> {
+        let keys = self.accounts.keys_as_vector();
+        let values = self.accounts.values_as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(MAX_ACCOUNTS_PAGE_SIZE).min(MAX_ACCOUNTS_PAGE_SIZE);
+        let to_index = from_index.saturating_add(limit).min(keys.len());
+        (from_index..to_index)
+            .map(|index| AccountStorageBalance {
+                account_id: keys.get(index).unwrap(),
+                storage_balance: values.get(index).unwrap().storage_balance(),
+            })
+            .collect()
+    }
+}
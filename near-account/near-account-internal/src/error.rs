@@ -0,0 +1,194 @@
+use std::fmt;
+
+use near_sdk::{env, AccountId, Balance, Gas};
+
+/// Errors of the accounts and of the plugins built on top of them
+///
+/// Each error has a stable code which does not change with the wording of the message.
+/// Errors are turned into panics with `unwrap_or_panic`, with the message formatted as `<code>: <message>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountsError {
+    /// The account is not registered
+    NotRegistered { account_id: AccountId },
+    /// The predecessor is not the owner of the accounts
+    NotOwner { owner_id: AccountId },
+    /// The account does not have enough free Near
+    InsufficientNear { required: Balance, available: Balance },
+    /// The attached deposit does not cover the minimum storage balance
+    DepositBelowMinimum { attached: Balance, min: Balance },
+    /// The maximum storage balance is set below the minimum
+    MaxBelowMinimum { max: Balance, min: Balance },
+    /// The account is already sponsored by another account
+    AlreadySponsored { sponsor_id: AccountId },
+    /// The account still holds balances and cannot be unregistered
+    HoldsBalances { account_id: AccountId },
+    /// The account does not have enough of a token
+    InsufficientBalance {
+        account_id: AccountId,
+        token_id: AccountId,
+        balance: Balance,
+        amount: Balance,
+    },
+    /// The spender is not allowed to spend that much of the owner's token
+    InsufficientAllowance {
+        owner_id: AccountId,
+        spender_id: AccountId,
+        token_id: AccountId,
+        allowance: Balance,
+        amount: Balance,
+    },
+    /// Deposits of the token are not allowed by the token policy
+    TokenNotAllowed { token_id: AccountId },
+    /// The message of a call could not be parsed
+    InvalidMessage { reason: String },
+    /// The result of a promise could not be parsed
+    InvalidPromiseResult { reason: String },
+    /// State kept in storage could not be read or written
+    InvalidState { reason: String },
+    /// The call requires an attached deposit of exactly 1 yoctoNEAR
+    NotOneYocto { attached: Balance },
+    /// The account tried to transfer a balance to itself
+    SelfTransfer { account_id: AccountId },
+    /// The account tried to approve itself as a spender
+    SelfApproval { account_id: AccountId },
+    /// More withdrawals were requested at once than fit in the gas limit
+    TooManyWithdrawals { count: usize, max: usize },
+    /// Not enough gas is left for the receiver of a call
+    InsufficientGas { required: Gas, available: Gas },
+}
+
+impl AccountsError {
+    /// The stable code of the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountsError::NotRegistered { .. } => "ERR_NOT_REGISTERED",
+            AccountsError::NotOwner { .. } => "ERR_NOT_OWNER",
+            AccountsError::InsufficientNear { .. } => "ERR_INSUFFICIENT_NEAR",
+            AccountsError::DepositBelowMinimum { .. } => "ERR_DEPOSIT_BELOW_MIN",
+            AccountsError::MaxBelowMinimum { .. } => "ERR_MAX_BELOW_MIN",
+            AccountsError::AlreadySponsored { .. } => "ERR_ALREADY_SPONSORED",
+            AccountsError::HoldsBalances { .. } => "ERR_HOLDS_BALANCES",
+            AccountsError::InsufficientBalance { .. } => "ERR_INSUFFICIENT_BALANCE",
+            AccountsError::InsufficientAllowance { .. } => "ERR_INSUFFICIENT_ALLOWANCE",
+            AccountsError::TokenNotAllowed { .. } => "ERR_TOKEN_NOT_ALLOWED",
+            AccountsError::InvalidMessage { .. } => "ERR_INVALID_MSG",
+            AccountsError::InvalidPromiseResult { .. } => "ERR_INVALID_PROMISE_RESULT",
+            AccountsError::InvalidState { .. } => "ERR_INVALID_STATE",
+            AccountsError::NotOneYocto { .. } => "ERR_NOT_ONE_YOCTO",
+            AccountsError::SelfTransfer { .. } => "ERR_SELF_TRANSFER",
+            AccountsError::SelfApproval { .. } => "ERR_SELF_APPROVAL",
+            AccountsError::TooManyWithdrawals { .. } => "ERR_TOO_MANY_WITHDRAWALS",
+            AccountsError::InsufficientGas { .. } => "ERR_INSUFFICIENT_GAS",
+        }
+    }
+}
+
+impl fmt::Display for AccountsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.code())?;
+        match self {
+            AccountsError::NotRegistered { account_id } => {
+                write!(f, "The account {} is not registered", account_id)
+            }
+            AccountsError::NotOwner { owner_id } => {
+                write!(f, "Only the owner {} can call this method", owner_id)
+            }
+            AccountsError::InsufficientNear { required, available } => write!(
+                f,
+                "Not enough Near to cover the transaction, required {} but only {} is available",
+                required, available
+            ),
+            AccountsError::DepositBelowMinimum { attached, min } => write!(
+                f,
+                "The attached deposit of {} is less than the minimum storage balance of {}",
+                attached, min
+            ),
+            AccountsError::MaxBelowMinimum { max, min } => write!(
+                f,
+                "The maximum storage balance of {} cannot be less than the minimum of {}",
+                max, min
+            ),
+            AccountsError::AlreadySponsored { sponsor_id } => {
+                write!(f, "The account is already sponsored by {}", sponsor_id)
+            }
+            AccountsError::HoldsBalances { account_id } => write!(
+                f,
+                "The account {} still holds balances, use force to forfeit them",
+                account_id
+            ),
+            AccountsError::InsufficientBalance { account_id, token_id, balance, amount } => write!(
+                f,
+                "The account {} has a balance of {} for token {}, requested amount {}",
+                account_id, balance, token_id, amount
+            ),
+            AccountsError::InsufficientAllowance {
+                owner_id,
+                spender_id,
+                token_id,
+                allowance,
+                amount,
+            } => write!(
+                f,
+                "The account {} is allowed to spend {} of token {} for {}, requested amount {}",
+                spender_id, allowance, token_id, owner_id, amount
+            ),
+            AccountsError::TokenNotAllowed { token_id } => {
+                write!(f, "Deposits of token {} are not allowed", token_id)
+            }
+            AccountsError::InvalidMessage { reason } => write!(f, "Invalid message: {}", reason),
+            AccountsError::InvalidPromiseResult { reason } => {
+                write!(f, "Invalid promise result: {}", reason)
+            }
+            AccountsError::InvalidState { reason } => write!(f, "Invalid state: {}", reason),
+            AccountsError::NotOneYocto { attached } => write!(
+                f,
+                "Requires an attached deposit of exactly 1 yoctoNEAR, attached {}",
+                attached
+            ),
+            AccountsError::SelfTransfer { account_id } => {
+                write!(f, "The account {} cannot transfer a balance to itself", account_id)
+            }
+            AccountsError::SelfApproval { account_id } => {
+                write!(f, "The account {} cannot approve itself as a spender", account_id)
+            }
+            AccountsError::TooManyWithdrawals { count, max } => {
+                write!(f, "Requested {} withdrawals, at most {} are allowed", count, max)
+            }
+            AccountsError::InsufficientGas { required, available } => write!(
+                f,
+                "Not enough gas for the receiver, required {} but only {} is available",
+                required, available
+            ),
+        }
+    }
+}
+
+pub type AccountsResult<T> = Result<T, AccountsError>;
+
+/// The single point where errors are turned into panics
+pub trait UnwrapOrPanic<T> {
+    fn unwrap_or_panic(self) -> T;
+}
+
+impl<T> UnwrapOrPanic<T> for AccountsResult<T> {
+    fn unwrap_or_panic(self) -> T {
+        match self {
+            Ok(value) => value,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+/// Check that exactly 1 yoctoNEAR is attached, so that the call has to be signed with a full access key
+pub fn check_one_yocto() -> AccountsResult<()> {
+    let attached = env::attached_deposit();
+    if attached != 1 {
+        return Err(AccountsError::NotOneYocto { attached });
+    }
+    Ok(())
+}
+
+/// Same as `check_one_yocto` but panics with the error
+pub fn assert_one_yocto() {
+    check_one_yocto().unwrap_or_panic()
+}
@@ -0,0 +1,88 @@
+use near_sdk::{
+    env,
+    serde_json::{json, Value},
+    AccountId, Balance,
+};
+
+/// Log a free-form message, compiled out without the `debug-logs` feature to save gas
+///
+/// The feature is checked in the crate which calls the macro, so plugins declare their own `debug-logs` feature
+#[macro_export]
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "debug-logs") {
+            near_sdk::log!($($arg)*)
+        }
+    };
+}
+
+pub const EVENT_STANDARD: &str = "near-accounts";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+/// Emit a NEP-297 event as an `EVENT_JSON:` log
+pub fn emit_event(standard: &str, version: &str, event: &str, data: Value) {
+    let event = json!({
+        "standard": standard,
+        "version": version,
+        "event": event,
+        "data": [data],
+    });
+    env::log(format!("EVENT_JSON:{}", event).as_bytes());
+}
+
+fn emit_account_event(event: &str, data: Value) {
+    emit_event(EVENT_STANDARD, EVENT_VERSION, event, data)
+}
+
+pub(crate) fn emit_register(account_id: &AccountId, depositor_id: &AccountId, amount: Balance) {
+    emit_account_event(
+        "storage_register",
+        json!({
+            "account_id": account_id,
+            "depositor_id": depositor_id,
+            "amount": amount.to_string(),
+        }),
+    )
+}
+
+pub(crate) fn emit_deposit(account_id: &AccountId, depositor_id: &AccountId, amount: Balance) {
+    emit_account_event(
+        "storage_deposit",
+        json!({
+            "account_id": account_id,
+            "depositor_id": depositor_id,
+            "amount": amount.to_string(),
+        }),
+    )
+}
+
+pub(crate) fn emit_withdraw(account_id: &AccountId, receiver_id: &AccountId, amount: Balance) {
+    emit_account_event(
+        "storage_withdraw",
+        json!({
+            "account_id": account_id,
+            "receiver_id": receiver_id,
+            "amount": amount.to_string(),
+        }),
+    )
+}
+
+pub(crate) fn emit_unregister(account_id: &AccountId, forced: bool) {
+    emit_account_event(
+        "storage_unregister",
+        json!({
+            "account_id": account_id,
+            "forced": forced,
+        }),
+    )
+}
+
+pub(crate) fn emit_refund(receiver_id: &AccountId, amount: Balance) {
+    emit_account_event(
+        "storage_refund",
+        json!({
+            "receiver_id": receiver_id,
+            "amount": amount.to_string(),
+        }),
+    )
+}
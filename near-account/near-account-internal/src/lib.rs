@@ -2,18 +2,32 @@ use near_contract_standards::storage_management::{
     StorageBalance, StorageBalanceBounds, StorageManagement,
 };
 use near_sdk::{
-    assert_one_yocto,
     borsh::{self, BorshDeserialize, BorshSerialize},
-    collections::UnorderedMap,
+    collections::{LookupMap, UnorderedMap},
     env::{self},
     json_types::{ValidAccountId, U128},
-    log, AccountId, Balance, Promise,
+    AccountId, Balance, IntoStorageKey, Promise, StorageUsage,
 };
 
 pub use account::Account;
 pub use account::{AccountDeposits, AccountInfoTrait};
+pub use accounting::AccountStorageUsage;
+pub use enumeration::{AccountStorageBalance, MAX_ACCOUNTS_PAGE_SIZE};
+pub use error::{assert_one_yocto, check_one_yocto, AccountsError, AccountsResult, UnwrapOrPanic};
+pub use events::emit_event;
+pub use registration::ParkedDeposit;
+pub use storage_payer::StoragePayer;
+
+#[macro_use]
+mod events;
 
 mod account;
+mod accounting;
+mod enumeration;
+mod error;
+mod registration;
+mod storage_payer;
+mod storage_reserve;
 
 pub trait NewInfo {
     fn default_from_account_id(account_id: AccountId) -> Self;
@@ -24,18 +38,40 @@ pub trait NewInfo {
 pub struct Accounts<AccountInfoUsed: AccountInfoTrait> {
     pub accounts: UnorderedMap<AccountId, Account<AccountInfoUsed>>,
     pub default_min_storage_bal: u128,
+    /// The maximum amount of Near an account can deposit, anything above is refunded
+    pub max_storage_bal: Option<u128>,
+    /// The account which can update the configuration of the accounts
+    pub owner_id: AccountId,
+    /// If set, `insert_account_check_storage` pays for storage with the caller's attached deposit
+    pub storage_from_attached_deposit: bool,
+    /// Near set aside by the owner to cover the storage of accounts within callbacks and of contract level state
+    pub storage_reserve: Balance,
+    /// Near parked to register accounts which are not registered yet, see `park_storage_deposit`
+    pub parked_deposits: LookupMap<AccountId, ParkedDeposit>,
+    /// The storage prefix of the accounts, state kept next to the accounts is stored under it
+    prefix: Vec<u8>,
+    /// Whether the attached deposit was already used in this call
+    #[borsh_skip]
+    attached_deposit_absorbed: bool,
 }
 
 impl<Info: AccountInfoTrait> Accounts<Info> {
     /// Get an account and panic if the account is not registered
     pub fn get_account_checked(&self, account_id: &AccountId) -> Account<Info> {
-        let account = self.accounts.get(account_id);
-        if account.is_none() {
-            panic!("Account {} is unregistered", account_id);
-        }
-        account.unwrap()
+        self.try_get_account(account_id).unwrap_or_panic()
+    }
+
+    /// Get an account or an error if the account is not registered
+    pub fn try_get_account(&self, account_id: &AccountId) -> AccountsResult<Account<Info>> {
+        self.accounts
+            .get(account_id)
+            .ok_or_else(|| AccountsError::NotRegistered { account_id: account_id.clone() })
     }
 
+    /// Call the closure and charge the account for the change in storage
+    ///
+    /// The account is written back to the map while storage is being measured so that
+    /// a change in the size of the top-level entry is also paid for
     pub fn check_storage<F, T: Sized>(
         &mut self,
         account: &mut Account<Info>,
@@ -45,9 +81,36 @@ impl<Info: AccountInfoTrait> Accounts<Info> {
     where
         F: FnOnce(&mut Accounts<Info>, &mut Account<Info>) -> T,
     {
-        let ret = account.check_storage(self, closure);
+        self.try_check_storage(account, account_id, closure).unwrap_or_panic()
+    }
+
+    /// Same as `check_storage` but returns an error if the account cannot pay for the storage
+    ///
+    /// Changes made by the closure are not reverted on error, so the caller is expected to panic
+    pub fn try_check_storage<F, T: Sized>(
+        &mut self,
+        account: &mut Account<Info>,
+        account_id: &AccountId,
+        closure: F,
+    ) -> AccountsResult<T>
+    where
+        F: FnOnce(&mut Accounts<Info>, &mut Account<Info>) -> T,
+    {
+        let ret = account.try_check_storage(self, |accounts, account| {
+            let ret = closure(accounts, account);
+            accounts.accounts.insert(account_id, account);
+            ret
+        })?;
+        // Persist the updated storage accounting
         self.accounts.insert(&account_id, &account);
-        ret
+        Ok(ret)
+    }
+
+    /// Remove an account and clear any nested state held by its info
+    pub fn remove_account(&mut self, account_id: &AccountId) -> Option<Account<Info>> {
+        let mut account = self.accounts.remove(account_id)?;
+        account.info.on_unregister(self);
+        Some(account)
     }
 
     pub fn remove_account_unchecked(
@@ -65,107 +128,344 @@ impl<Info: AccountInfoTrait> Accounts<Info> {
         self.accounts.insert(account_id, account)
     }
 
+    /// Insert the account and charge it for the change in storage
+    ///
+    /// If `storage_from_attached_deposit` is set and the account is the caller's, the attached deposit is
+    /// added to the account before checking storage and whatever is not used for storage is refunded
     pub fn insert_account_check_storage(
         &mut self,
         account_id: &AccountId,
         account: &mut Account<Info>,
     ) -> Option<Account<Info>> {
-        self.check_storage(account, account_id, |accounts, account| {
+        let amount_absorbed = self.absorb_attached_deposit(account_id, account);
+        let near_used_prior = account.near_used_for_storage;
+
+        let ret = self.check_storage(account, account_id, |accounts, account| {
             accounts.accounts.insert(account_id, account)
-        })
+        });
+
+        if amount_absorbed != 0 {
+            let amount_used =
+                account.near_used_for_storage.saturating_sub(near_used_prior).min(amount_absorbed);
+            let amount_refund = amount_absorbed - amount_used;
+            if amount_refund != 0 {
+                account.near_amount -= amount_refund;
+                self.accounts.insert(account_id, account);
+                events::emit_refund(&env::predecessor_account_id(), amount_refund);
+                Promise::new(env::predecessor_account_id()).transfer(amount_refund);
+            }
+        }
+        ret
+    }
+
+    /// Add the attached deposit to the caller's account, at most once per call
+    fn absorb_attached_deposit(
+        &mut self,
+        account_id: &AccountId,
+        account: &mut Account<Info>,
+    ) -> Balance {
+        if !self.storage_from_attached_deposit
+            || self.attached_deposit_absorbed
+            || *account_id != env::predecessor_account_id()
+        {
+            return 0;
+        }
+        self.attached_deposit_absorbed = true;
+        let amount = env::attached_deposit();
+        account.near_amount += amount;
+        amount
     }
 
     pub fn get_account(&self, account_id: &AccountId) -> Option<Account<Info>> {
         self.accounts.get(account_id)
     }
+
+    /// Get a storage key under the prefix of the accounts, i.e. for a plugin's state kept next to them
+    pub fn storage_key(&self, suffix: &[u8]) -> Vec<u8> {
+        [self.prefix.as_slice(), suffix].concat()
+    }
 }
 
 impl<Info: AccountInfoTrait> Accounts<Info> {
     pub fn new() -> Self {
+        Self::new_with_prefix(b"accounts-map".to_vec())
+    }
+
+    /// Create the accounts with a custom storage prefix
+    ///
+    /// Each `Accounts` in a contract needs its own prefix (i.e. a variant of a `BorshStorageKey` enum)
+    /// so that multiple account registries do not collide in storage
+    pub fn new_with_prefix<S: IntoStorageKey>(prefix: S) -> Self {
+        Self::new_with_max_storage_bal(prefix, None)
+    }
+
+    /// Create the accounts with a maximum storage balance per account
+    ///
+    /// The contract's account is the owner of the accounts, use `set_owner` to change it
+    pub fn new_with_max_storage_bal<S: IntoStorageKey>(
+        prefix: S,
+        max_storage_bal: Option<u128>,
+    ) -> Self {
+        let prefix = prefix.into_storage_key();
         let mut ret = Accounts::<Info> {
-            accounts: UnorderedMap::new(b"accounts-map".to_vec()),
+            accounts: UnorderedMap::new(prefix.clone()),
             default_min_storage_bal: 0,
+            max_storage_bal: None,
+            owner_id: env::current_account_id(),
+            storage_from_attached_deposit: false,
+            storage_reserve: 0,
+            parked_deposits: LookupMap::new([prefix.clone(), b"-parked".to_vec()].concat()),
+            prefix,
+            attached_deposit_absorbed: false,
         };
-        ret.default_min_storage_bal = ret.get_storage_cost(None, true);
+        // Measured with the longest sponsor id, so that the minimum also covers a sponsored registration
+        ret.default_min_storage_bal = ret.get_storage_cost(None, Some(&"b".repeat(64)), true);
+        ret.internal_set_max_storage_bal(max_storage_bal).unwrap_or_panic();
         ret
     }
 }
 
+/// Owner gated configuration
+impl<Info: AccountInfoTrait> Accounts<Info> {
+    pub fn assert_owner(&self) {
+        self.check_owner().unwrap_or_panic()
+    }
+
+    /// Check that the predecessor is the owner
+    pub fn check_owner(&self) -> AccountsResult<()> {
+        if env::predecessor_account_id() != self.owner_id {
+            return Err(AccountsError::NotOwner { owner_id: self.owner_id.clone() });
+        }
+        Ok(())
+    }
+
+    pub fn set_owner(&mut self, owner_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.owner_id = owner_id;
+    }
+
+    /// Set the maximum storage balance, `None` allows for unlimited deposits
+    pub fn set_max_storage_bal(&mut self, max_storage_bal: Option<u128>) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.internal_set_max_storage_bal(max_storage_bal).unwrap_or_panic();
+    }
+
+    /// Opt into paying for storage with the attached deposit in `insert_account_check_storage`,
+    /// which lets callers pay as they go instead of depositing upfront
+    pub fn set_storage_from_attached_deposit(&mut self, enabled: bool) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.storage_from_attached_deposit = enabled;
+    }
+
+    fn internal_set_max_storage_bal(
+        &mut self,
+        max_storage_bal: Option<u128>,
+    ) -> AccountsResult<()> {
+        if let Some(max) = max_storage_bal {
+            if max < self.default_min_storage_bal {
+                return Err(AccountsError::MaxBelowMinimum {
+                    max,
+                    min: self.default_min_storage_bal,
+                });
+            }
+        }
+        self.max_storage_bal = max_storage_bal;
+        Ok(())
+    }
+}
+
 impl<Info: AccountInfoTrait> Accounts<Info> {
     /// Get the cost of storage
+    /// * `sponsor_id` - the sponsor recorded in the default account, as its bytes are charged as well
     /// * `unregister` - if set to false then the get_storage_cost will also register the default account with the account id
     pub(crate) fn get_storage_cost(
         &mut self,
         account_id: Option<AccountId>,
+        sponsor_id: Option<&AccountId>,
         unregister: bool,
     ) -> u128 {
+        self.get_storage_usage(account_id, sponsor_id, unregister) as u128
+            * env::storage_byte_cost()
+    }
+
+    /// Get the number of bytes used by registering a default account
+    /// * `sponsor_id` - the sponsor recorded in the default account
+    /// * `unregister` - if set to false then the default account will be registered with the account id
+    pub(crate) fn get_storage_usage(
+        &mut self,
+        account_id: Option<AccountId>,
+        sponsor_id: Option<&AccountId>,
+        unregister: bool,
+    ) -> StorageUsage {
         let storage_prior = env::storage_usage();
         let account_id = account_id.unwrap_or("a".repeat(64));
-        let default_account = Account::default_from_account_id(account_id.clone());
+        let mut default_account = Account::default_from_account_id(account_id.clone());
+        if let Some(sponsor_id) = sponsor_id {
+            default_account.add_sponsored(sponsor_id, 0);
+        }
         self.accounts.insert(&account_id, &default_account);
 
-        // Get the storage cost
-        let storage_cost =
-            (env::storage_usage() - storage_prior) as u128 * env::storage_byte_cost();
+        let storage_usage = env::storage_usage() - storage_prior;
         if unregister {
             self.accounts.remove(&account_id);
+        } else {
+            default_account.storage_usage = storage_usage;
+            self.accounts.insert(&account_id, &default_account);
         }
-        storage_cost
+        storage_usage
     }
 }
 
-/// storage handlers
-impl<Info: AccountInfoTrait> StorageManagement for Accounts<Info> {
-    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
-        if force.unwrap_or(false) == false {
-            log!("Can only unregister if force is true");
-            false
-        } else {
-            // TODO: make macro for this (sep lib)
-            assert_eq!(env::attached_deposit(), 1, "Expected 1 Near");
-            let account_id = env::predecessor_account_id();
-            let lookup = self.accounts.remove(&account_id);
-            if lookup.is_none() {
-                panic!("Cannot unregister a non-existant account");
-            } else {
-                log!("Deleting account {}", account_id);
-                let account = lookup.unwrap();
-                Promise::new(env::predecessor_account_id()).transfer(account.near_amount);
+impl<Info: AccountInfoTrait> Accounts<Info> {
+    /// Unregister the predecessor's account
+    ///
+    /// Without balances the account can always be unregistered. If it still holds balances, this panics
+    /// unless `force` is set, in which case the balances are forfeited to the `on_forfeit` callback
+    pub fn storage_unregister_with_forfeit<F>(&mut self, force: Option<bool>, on_forfeit: F) -> bool
+    where
+        F: FnOnce(&mut Accounts<Info>, &AccountId, &mut Account<Info>),
+    {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account = match self.accounts.get(&account_id) {
+            Some(account) => account,
+            None => {
+                debug_log!("Account {} is not registered", account_id);
+                return false;
             }
-            true
+        };
+
+        self.accounts.remove(&account_id);
+        let forced = !account.info.can_unregister();
+        if forced {
+            if !force.unwrap_or(false) {
+                Err(AccountsError::HoldsBalances { account_id: account_id.clone() })
+                    .unwrap_or_panic()
+            }
+            debug_log!("Forfeiting the balances of {}", account_id);
+            on_forfeit(self, &account_id, &mut account);
         }
+        account.info.on_unregister(self);
+
+        debug_log!("Deleting account {}", account_id);
+        events::emit_unregister(&account_id, forced);
+        let storage_debt = account.storage_debt;
+        self.repay_storage_debt(&mut account, storage_debt);
+        let amount_sponsored = account.sponsored_amount.min(account.near_amount);
+        if amount_sponsored != 0 {
+            let sponsor_id = account.sponsor_id.clone().unwrap();
+            events::emit_refund(&sponsor_id, amount_sponsored);
+            Promise::new(sponsor_id).transfer(amount_sponsored);
+        }
+        let amount_refund = account.near_amount - amount_sponsored;
+        if amount_refund != 0 {
+            events::emit_refund(&account_id, amount_refund);
+            Promise::new(account_id).transfer(amount_refund);
+        }
+        true
     }
+}
 
-    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
-        assert_one_yocto();
+impl<Info: AccountInfoTrait> Accounts<Info> {
+    /// Same as `storage_withdraw` but returns an error instead of panicking
+    pub fn try_storage_withdraw(&mut self, amount: Option<U128>) -> AccountsResult<StorageBalance> {
+        check_one_yocto()?;
         let predecessor_account_id = env::predecessor_account_id();
-        if let Some(mut account) = self.accounts.get(&predecessor_account_id) {
-            let amount = match amount {
-                Some(amount) => {
-                    if amount.0 > account.get_available_near() {
-                        panic!("The amount is greater than the available storage balance");
-                    } else {
-                        amount.0
-                    }
-                }
-                _ => account.get_available_near(),
-            };
+        let mut account = self.try_get_account(&predecessor_account_id)?;
+        let available_user_near = account.get_available_user_near();
+        let (amount, amount_sponsored) = match amount {
+            Some(amount) if amount.0 > available_user_near => {
+                return Err(AccountsError::InsufficientNear {
+                    required: amount.0,
+                    available: available_user_near,
+                });
+            }
+            Some(amount) => (amount.0, 0),
+            None => (available_user_near, account.get_available_sponsored_near()),
+        };
 
-            account.near_amount -= amount;
-            self.accounts.insert(&predecessor_account_id, &account);
-            Promise::new(env::predecessor_account_id()).transfer(amount);
-            account.storage_balance()
-        } else {
-            env::panic(
-                format!("The account {} is not registered", &predecessor_account_id).as_bytes(),
-            );
+        let sponsor_id = account.sponsor_id.clone();
+        account.near_amount -= amount + amount_sponsored;
+        account.remove_sponsored(amount_sponsored);
+        self.accounts.insert(&predecessor_account_id, &account);
+        if amount != 0 {
+            events::emit_withdraw(&predecessor_account_id, &predecessor_account_id, amount);
+            Promise::new(predecessor_account_id.clone()).transfer(amount);
+        }
+        if amount_sponsored != 0 {
+            let sponsor_id = sponsor_id.unwrap();
+            events::emit_withdraw(&predecessor_account_id, &sponsor_id, amount_sponsored);
+            Promise::new(sponsor_id).transfer(amount_sponsored);
+        }
+        Ok(account.storage_balance())
+    }
+
+    /// Get how much of the attached amount can be deposited into an account holding `near_amount`
+    fn get_deposit_amount(&self, near_amount: Balance, amount_attached: Balance) -> Balance {
+        match self.max_storage_bal {
+            Some(max) => amount_attached.min(max.saturating_sub(near_amount)),
+            None => amount_attached,
+        }
+    }
+
+    /// Register a new account with `near_amount` deposited and charge it for the account's storage
+    ///
+    /// The sponsor is recorded before the storage is measured, so that its bytes are charged as well
+    fn internal_register_account(
+        &mut self,
+        account_id: &AccountId,
+        near_amount: Balance,
+        sponsor_id: Option<&AccountId>,
+    ) -> AccountsResult<Account<Info>> {
+        let mut account = Account::default_from_account_id(account_id.clone());
+        account.near_amount = near_amount;
+        if let Some(sponsor_id) = sponsor_id {
+            account.add_sponsored(sponsor_id, near_amount);
+        }
+        let storage_prior = env::storage_usage();
+        self.accounts.insert(account_id, &account);
+        let storage_usage = env::storage_usage() - storage_prior;
+        let storage_cost = storage_usage as u128 * env::storage_byte_cost();
+        if near_amount < storage_cost {
+            self.accounts.remove(account_id);
+            return Err(AccountsError::InsufficientNear {
+                required: storage_cost,
+                available: near_amount,
+            });
         }
+
+        account.near_used_for_storage = storage_cost;
+        account.storage_usage = storage_usage;
+        self.accounts.insert(account_id, &account);
+        Ok(account)
+    }
+}
+
+/// storage handlers
+impl<Info: AccountInfoTrait> StorageManagement for Accounts<Info> {
+    /// Balances can only be forfeited with an explicit callback, see `storage_unregister_with_forfeit`
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        // Without a forfeit callback, balances cannot be forfeited even with force
+        self.storage_unregister_with_forfeit(force, |_, account_id, _| {
+            Err(AccountsError::HoldsBalances { account_id: account_id.clone() }).unwrap_or_panic()
+        })
+    }
+
+    /// Withdraw the predecessor's Near which is not used for storage
+    ///
+    /// Only Near deposited by the account itself can be withdrawn to it. When withdrawing everything
+    /// (`amount` is None), the sponsor's unused Near is also returned to the sponsor
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        self.try_storage_withdraw(amount).unwrap_or_panic()
     }
 
     fn storage_balance_bounds(&self) -> StorageBalanceBounds {
         StorageBalanceBounds {
             min: self.default_min_storage_bal.into(),
-            max: None,
+            max: self.max_storage_bal.map(|max| max.into()),
         }
     }
 
@@ -177,58 +477,537 @@ impl<Info: AccountInfoTrait> StorageManagement for Accounts<Info> {
         }
     }
 
+    /// Deposit Near for an account's storage
+    ///
+    /// * For a new account, the deposit must be at least the minimum storage balance. With `registration_only`
+    ///   exactly the minimum is deposited, otherwise the deposit is capped at the maximum storage balance
+    /// * For an existing account, `registration_only` refunds the whole deposit, otherwise the deposit tops up
+    ///   the account up to the maximum storage balance
+    ///
+    /// Any Near which is not deposited is refunded to the predecessor. If the predecessor is not the account,
+    /// the deposit is recorded as sponsored by the predecessor
     fn storage_deposit(
         &mut self,
         account_id: Option<ValidAccountId>,
         registration_only: Option<bool>,
     ) -> near_contract_standards::storage_management::StorageBalance {
         let registration_only = registration_only.unwrap_or(false);
-        let account_id: AccountId = account_id
-            .map(|a| a.into())
-            .unwrap_or(env::predecessor_account_id());
+        let depositor_id = env::predecessor_account_id();
+        let account_id: AccountId = account_id.map(|a| a.into()).unwrap_or(depositor_id.clone());
         let amount_attached = env::attached_deposit();
-        let registered = self.accounts.get(&account_id);
-
-        if registered.is_some() && registration_only {
-            log!("Account already registered");
-            Promise::new(env::predecessor_account_id()).transfer(amount_attached);
-            let account = registered.unwrap();
-            account.storage_balance()
-        } else if registered.is_some() {
-            let mut account = registered.unwrap();
-            account.near_amount += amount_attached;
-            self.accounts.insert(&account_id, &account);
-            account.storage_balance()
-        } else {
-            // NOTE: get_storage also registers the account id here
-            let storage_cost = self.get_storage_cost(Some(account_id.clone()), false);
-            let min_storage_cost = self.storage_balance_bounds().min.0;
-            if amount_attached < storage_cost || amount_attached < min_storage_cost {
-                self.accounts.remove(&account_id);
-                Promise::new(env::predecessor_account_id()).transfer(amount_attached);
-                StorageBalance {
-                    available: 0.into(),
-                    total: 0.into(),
+
+        let (account, amount_refund) = match self.accounts.get(&account_id) {
+            Some(account) if registration_only => {
+                debug_log!("Account already registered");
+                (account, amount_attached)
+            }
+            Some(mut account) => {
+                let amount_deposited =
+                    self.get_deposit_amount(account.near_amount, amount_attached);
+                // Recording the sponsor can grow the account, which is charged like any other change
+                self.check_storage(&mut account, &account_id, |accounts, account| {
+                    account.near_amount += amount_deposited;
+                    let amount_repaid = accounts.repay_storage_debt(account, amount_deposited);
+                    let amount_sponsored = amount_deposited - amount_repaid;
+                    if depositor_id != account_id && amount_sponsored != 0 {
+                        accounts.release_storage_payer(account, &depositor_id);
+                        account.add_sponsored(&depositor_id, amount_sponsored);
+                    }
+                });
+                if amount_deposited != 0 {
+                    events::emit_deposit(&account_id, &depositor_id, amount_deposited);
                 }
-            } else if registration_only {
-                let amount_refund = storage_cost - amount_attached;
-                let mut account = self.accounts.get(&account_id).unwrap();
-                account.near_amount = storage_cost;
-                account.near_used_for_storage = storage_cost;
-                self.accounts.insert(&account_id, &account);
-
-                if amount_refund != 0 {
-                    Promise::new(env::predecessor_account_id()).transfer(amount_refund);
+                (account, amount_attached - amount_deposited)
+            }
+            None => {
+                let min_storage_bal = self.default_min_storage_bal;
+                if amount_attached < min_storage_bal {
+                    Err(AccountsError::DepositBelowMinimum {
+                        attached: amount_attached,
+                        min: min_storage_bal,
+                    })
+                    .unwrap_or_panic()
                 }
-                account.storage_balance()
-            } else {
-                let mut account = self.accounts.get(&account_id).unwrap();
-                account.near_amount = amount_attached;
-                account.near_used_for_storage = storage_cost;
-                self.accounts.insert(&account_id, &account);
-
-                account.storage_balance()
+                let amount_deposited = if registration_only {
+                    min_storage_bal
+                } else {
+                    self.get_deposit_amount(0, amount_attached)
+                };
+                let sponsor_id = Some(&depositor_id).filter(|id| **id != account_id);
+                let account = self
+                    .internal_register_account(&account_id, amount_deposited, sponsor_id)
+                    .unwrap_or_panic();
+                events::emit_register(&account_id, &depositor_id, amount_deposited);
+                (account, amount_attached - amount_deposited)
             }
+        };
+
+        if amount_refund != 0 {
+            events::emit_refund(&depositor_id, amount_refund);
+            Promise::new(depositor_id).transfer(amount_refund);
+        }
+        account.storage_balance()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    const INIT_ACCOUNT_BAL: u128 = 10_000_000_000_000_000_000_000_000;
+
+    use std::convert::TryFrom;
+
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct Info {
+        pub message: String,
+    }
+
+    impl NewInfo for Info {
+        fn default_from_account_id(_account_id: AccountId) -> Self {
+            Self { message: "".to_string() }
+        }
+    }
+
+    impl AccountInfoTrait for Info {}
+
+    // mock the context for testing, notice "signer_account_id" that was accessed above from env::
+    fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id)
+            .account_balance(INIT_ACCOUNT_BAL);
+        builder
+    }
+
+    fn get_near_amount(near_accounts: &Accounts<Info>, account_id: ValidAccountId) -> Balance {
+        near_accounts.get_account_checked(&account_id.into()).near_amount
+    }
+
+    #[test]
+    fn test_deposit_new_account() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min * 3).build());
+        let bal = near_accounts.storage_deposit(None, None);
+        assert_eq!(bal.total.0, min * 3);
+        assert_eq!(get_near_amount(&near_accounts, accounts(1)), min * 3);
+
+        let account = near_accounts.get_account_checked(&accounts(1).into());
+        assert_eq!(
+            account.near_used_for_storage,
+            account.storage_usage as u128 * env::storage_byte_cost()
+        );
+        assert_eq!(bal.available.0, min * 3 - account.near_used_for_storage);
+    }
+
+    #[test]
+    fn test_deposit_new_account_registration_only() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min * 3).build());
+        let bal = near_accounts.storage_deposit(None, Some(true));
+        assert_eq!(bal.total.0, min);
+    }
+
+    #[test]
+    fn test_deposit_for_other_account() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min).build());
+        let storage_prior = env::storage_usage();
+        near_accounts.storage_deposit(Some(accounts(2)), None);
+        assert_eq!(get_near_amount(&near_accounts, accounts(2)), min);
+        assert!(near_accounts.get_account(&accounts(1).into()).is_none());
+
+        let account = near_accounts.get_account_checked(&accounts(2).into());
+        assert_eq!(account.sponsor_id, Some(accounts(1).into()));
+        assert_eq!(account.sponsored_amount, min);
+        // The bytes of the sponsor are charged as well
+        assert_eq!(account.storage_usage, env::storage_usage() - storage_prior);
+    }
+
+    #[test]
+    fn test_sponsored_registration_of_min_with_longest_ids() {
+        let sponsor_id = ValidAccountId::try_from("c".repeat(64)).unwrap();
+        let account_id = ValidAccountId::try_from("d".repeat(64)).unwrap();
+        let mut context = get_context(sponsor_id.clone());
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min).build());
+        let bal = near_accounts.storage_deposit(Some(account_id.clone()), Some(true));
+        assert_eq!(bal.total.0, min);
+        let account = near_accounts.get_account_checked(&account_id.into());
+        assert_eq!(account.sponsor_id, Some(sponsor_id.into()));
+        assert!(account.near_used_for_storage <= min);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ALREADY_SPONSORED")]
+    fn test_deposit_from_second_sponsor() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min).build());
+        near_accounts.storage_deposit(Some(accounts(2)), None);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        near_accounts.storage_deposit(Some(accounts(2)), None);
+    }
+
+    #[test]
+    fn test_withdraw_sponsored() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        // The sponsor pays for storage and the account deposits on top
+        testing_env!(context.attached_deposit(min * 2).build());
+        near_accounts.storage_deposit(Some(accounts(2)), None);
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, None);
+
+        let account = near_accounts.get_account_checked(&accounts(2).into());
+        let available_sponsored = account.get_available_sponsored_near();
+        assert_eq!(available_sponsored, min * 2 - account.near_used_for_storage);
+        assert_eq!(account.get_available_user_near(), min);
+
+        // Only the account's own Near can be withdrawn to it
+        testing_env!(context.attached_deposit(1).build());
+        let bal = near_accounts.storage_withdraw(Some(U128::from(min / 2)));
+        assert_eq!(bal.total.0, min * 3 - min / 2);
+
+        // Withdrawing everything returns the unused sponsored Near to the sponsor
+        let bal = near_accounts.storage_withdraw(None);
+        let account = near_accounts.get_account_checked(&accounts(2).into());
+        assert_eq!(bal.available.0, 0);
+        assert_eq!(account.sponsored_amount, account.near_used_for_storage);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_NEAR")]
+    fn test_withdraw_sponsored_to_account() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min * 2).build());
+        near_accounts.storage_deposit(Some(accounts(2)), None);
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(1).build());
+        near_accounts.storage_withdraw(Some(U128::from(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DEPOSIT_BELOW_MIN")]
+    fn test_deposit_new_account_below_min() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min - 1).build());
+        near_accounts.storage_deposit(None, Some(true));
+    }
+
+    #[test]
+    fn test_deposit_existing_account_registration_only() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min).build());
+        near_accounts.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(min * 2).build());
+        let bal = near_accounts.storage_deposit(None, Some(true));
+        assert_eq!(bal.total.0, min);
+        assert_eq!(get_near_amount(&near_accounts, accounts(1)), min);
+    }
+
+    #[test]
+    fn test_deposit_existing_account_top_up() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min).build());
+        near_accounts.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(min * 2).build());
+        let bal = near_accounts.storage_deposit(None, None);
+        assert_eq!(bal.total.0, min * 3);
+        assert_eq!(get_near_amount(&near_accounts, accounts(1)), min * 3);
+    }
+
+    #[test]
+    fn test_deposit_capped_at_max() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let min = Accounts::<Info>::new().storage_balance_bounds().min.0;
+        let mut near_accounts =
+            Accounts::<Info>::new_with_max_storage_bal(b"capped".to_vec(), Some(min * 2));
+        assert_eq!(near_accounts.storage_balance_bounds().max, Some(U128::from(min * 2)));
+
+        // A new account is capped at the max
+        testing_env!(context.attached_deposit(min * 3).build());
+        let bal = near_accounts.storage_deposit(None, None);
+        assert_eq!(bal.total.0, min * 2);
+
+        // A top up is capped at the max
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(min * 3).build());
+        let bal = near_accounts.storage_deposit(None, None);
+        assert_eq!(bal.total.0, min * 2);
+
+        // An account at the max cannot be topped up
+        testing_env!(context.attached_deposit(min).build());
+        let bal = near_accounts.storage_deposit(None, None);
+        assert_eq!(bal.total.0, min * 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_BELOW_MIN")]
+    fn test_max_below_min() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+        near_accounts.internal_set_max_storage_bal(Some(min - 1)).unwrap_or_panic();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_OWNER")]
+    fn test_set_max_not_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        testing_env!(context.attached_deposit(1).build());
+        near_accounts.set_max_storage_bal(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_OWNER")]
+    fn test_set_storage_from_attached_deposit_not_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        testing_env!(context.attached_deposit(1).build());
+        near_accounts.set_storage_from_attached_deposit(true);
+    }
+
+    #[test]
+    fn test_storage_from_attached_deposit() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        near_accounts.set_storage_from_attached_deposit(true);
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+        let account_id: AccountId = accounts(1).into();
+        let mut account = near_accounts.get_account_checked(&account_id);
+        let near_amount_prior = account.near_amount;
+        assert_eq!(account.get_available_near(), min - account.near_used_for_storage);
+
+        // Only the storage cost is kept from the attached deposit
+        testing_env!(context.attached_deposit(min).build());
+        account.info.message = "a".repeat(10);
+        near_accounts.insert_account_check_storage(&account_id, &mut account);
+        let account = near_accounts.get_account_checked(&account_id);
+        assert_eq!(account.near_amount, near_amount_prior + 10 * env::storage_byte_cost());
+    }
+
+    #[test]
+    fn test_storage_reserve_lends_in_callbacks() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+
+        // A callback writes more than the account can pay for
+        let account_id: AccountId = accounts(1).into();
+        let mut account = near_accounts.get_account_checked(&account_id);
+        let message_len = account.get_available_near() / env::storage_byte_cost() + 10;
+        account.info.message = "a".repeat(message_len as usize);
+        near_accounts.insert_account_check_storage(&account_id, &mut account);
+
+        let account = near_accounts.get_account_checked(&account_id);
+        let storage_debt = 10 * env::storage_byte_cost();
+        assert_eq!(account.storage_debt, storage_debt);
+        assert_eq!(account.get_available_near(), 0);
+        assert_eq!(near_accounts.storage_reserve, min - storage_debt);
+
+        // The debt is repaid on the next deposit
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(min).build());
+        let bal = near_accounts.storage_deposit(None, None);
+        assert_eq!(bal.available.0, min - storage_debt);
+        assert_eq!(near_accounts.get_account_checked(&account_id).storage_debt, 0);
+        assert_eq!(near_accounts.storage_reserve, min);
+    }
+
+    #[test]
+    fn test_sponsored_deposit_repays_storage_debt() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+
+        let account_id: AccountId = accounts(1).into();
+        let mut account = near_accounts.get_account_checked(&account_id);
+        let message_len = account.get_available_near() / env::storage_byte_cost() + 10;
+        account.info.message = "a".repeat(message_len as usize);
+        near_accounts.insert_account_check_storage(&account_id, &mut account);
+        let storage_debt = near_accounts.get_account_checked(&account_id).storage_debt;
+
+        // Only what is left after repaying the debt is sponsored
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(min).build());
+        near_accounts.storage_deposit(Some(accounts(1)), None);
+        let account = near_accounts.get_account_checked(&account_id);
+        assert_eq!(account.storage_debt, 0);
+        assert_eq!(account.sponsor_id, Some(accounts(2).into()));
+        assert_eq!(account.sponsored_amount, min - storage_debt);
+        assert_eq!(
+            account.near_used_for_storage,
+            account.storage_usage as u128 * env::storage_byte_cost()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_NEAR")]
+    fn test_storage_reserve_not_used_outside_callbacks() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+
+        let account_id: AccountId = accounts(1).into();
+        let mut account = near_accounts.get_account_checked(&account_id);
+        let message_len = account.get_available_near() / env::storage_byte_cost() + 10;
+        account.info.message = "a".repeat(message_len as usize);
+        near_accounts.insert_account_check_storage(&account_id, &mut account);
+    }
+
+    #[test]
+    fn test_try_storage_withdraw_errors() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(1).build());
+        let mut near_accounts = Accounts::<Info>::new();
+
+        let err = near_accounts.try_storage_withdraw(None).err().unwrap();
+        assert_eq!(err, AccountsError::NotRegistered { account_id: accounts(1).into() });
+        assert_eq!(err.code(), "ERR_NOT_REGISTERED");
+        assert!(err.to_string().starts_with("ERR_NOT_REGISTERED: "));
+
+        let min = near_accounts.storage_balance_bounds().min.0;
+        testing_env!(context.attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+        // Withdraw all the free Near so that nothing is available
+        testing_env!(context.attached_deposit(1).build());
+        near_accounts.storage_withdraw(None);
+        let err = near_accounts.try_storage_withdraw(Some(U128(1))).err().unwrap();
+        assert_eq!(err, AccountsError::InsufficientNear { required: 1, available: 0 });
+
+        testing_env!(context.attached_deposit(2).build());
+        let err = near_accounts.try_storage_withdraw(None).err().unwrap();
+        assert_eq!(err, AccountsError::NotOneYocto { attached: 2 });
+        assert_eq!(err.code(), "ERR_NOT_ONE_YOCTO");
+    }
+
+    #[test]
+    fn test_deposit_emits_events() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min * 2).build());
+        near_accounts.storage_deposit(None, Some(true));
+
+        let events: Vec<near_sdk::serde_json::Value> = near_sdk::test_utils::get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| near_sdk::serde_json::from_str(event).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["standard"], "near-accounts");
+        assert_eq!(events[0]["event"], "storage_register");
+        assert_eq!(events[0]["data"][0]["amount"], min.to_string());
+        assert_eq!(events[1]["event"], "storage_refund");
+        assert_eq!(events[1]["data"][0]["amount"], min.to_string());
+    }
+
+    #[test]
+    fn test_accounts_list() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        for i in 1..4 {
+            testing_env!(context.attached_deposit(min).build());
+            near_accounts.storage_deposit(Some(accounts(i)), None);
         }
+        assert_eq!(near_accounts.accounts_count(), 3);
+
+        let page = near_accounts.accounts_list(Some(1), Some(5));
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].account_id, AccountId::from(accounts(2)));
+        assert_eq!(page[0].storage_balance.total.0, min);
+        assert_eq!(page[1].account_id, AccountId::from(accounts(3)));
+        assert!(near_accounts.accounts_list(Some(3), None).is_empty());
+    }
+
+    #[test]
+    fn test_unregister() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(1).build());
+        assert!(!near_accounts.storage_unregister(None));
+
+        testing_env!(context.attached_deposit(min).build());
+        near_accounts.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(1).build());
+        assert!(near_accounts.storage_unregister(None));
+        assert!(near_accounts.get_account(&accounts(1).into()).is_none());
     }
 }
@@ -0,0 +1,120 @@
+use near_sdk::{
+    borsh::{self, BorshDeserialize, BorshSerialize},
+    env, AccountId, Balance, Promise,
+};
+
+use crate::{
+    assert_one_yocto, events, AccountInfoTrait, Accounts, AccountsError, AccountsResult,
+    UnwrapOrPanic,
+};
+
+/// Near deposited ahead of time to register an account, i.e. on its first token deposit
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct ParkedDeposit {
+    /// The account which parked the deposit
+    pub parker_id: AccountId,
+    pub amount: Balance,
+}
+
+/// Registering accounts without a storage deposit from the account itself
+impl<Info: AccountInfoTrait> Accounts<Info> {
+    /// Park the attached deposit to register `account_id` later on
+    ///
+    /// The parked amount has to be at least the minimum storage balance, which also pays for the parked entry.
+    /// If the account is registered from the parked deposit, the parker becomes its sponsor
+    pub fn park_storage_deposit(&mut self, account_id: &AccountId) -> Balance {
+        let parker_id = env::predecessor_account_id();
+        let mut parked = self
+            .parked_deposits
+            .get(account_id)
+            .unwrap_or(ParkedDeposit { parker_id: parker_id.clone(), amount: 0 });
+        if parked.parker_id != parker_id {
+            Err(AccountsError::AlreadySponsored { sponsor_id: parked.parker_id.clone() })
+                .unwrap_or_panic()
+        }
+        parked.amount += env::attached_deposit();
+        if parked.amount < self.default_min_storage_bal {
+            Err(AccountsError::DepositBelowMinimum {
+                attached: parked.amount,
+                min: self.default_min_storage_bal,
+            })
+            .unwrap_or_panic()
+        }
+        self.parked_deposits.insert(account_id, &parked);
+        parked.amount
+    }
+
+    /// Return a parked deposit which was not used to the parker
+    pub fn withdraw_parked_storage_deposit(&mut self, account_id: &AccountId) -> Balance {
+        assert_one_yocto();
+        let parker_id = env::predecessor_account_id();
+        let amount = match self.parked_deposits.get(account_id) {
+            Some(parked) if parked.parker_id == parker_id => parked.amount,
+            _ => 0,
+        };
+        if amount != 0 {
+            self.parked_deposits.remove(account_id);
+            events::emit_refund(&parker_id, amount);
+            Promise::new(parker_id).transfer(amount);
+        }
+        amount
+    }
+
+    pub fn get_parked_storage_deposit(&self, account_id: &AccountId) -> Balance {
+        self.parked_deposits.get(account_id).map(|parked| parked.amount).unwrap_or(0)
+    }
+
+    /// Register an account with the Near parked for it
+    ///
+    /// The parked deposit has to cover the account's storage and `additional_storage_cost`,
+    /// i.e. for what the caller writes to the account right after registering it
+    pub fn try_register_from_parked_deposit(
+        &mut self,
+        account_id: &AccountId,
+        additional_storage_cost: Balance,
+    ) -> AccountsResult<()> {
+        let parked = self.parked_deposits.get(account_id);
+        let parker_id =
+            parked.as_ref().map(|parked| &parked.parker_id).filter(|id| *id != account_id);
+        let storage_cost = self.get_storage_cost(Some(account_id.clone()), parker_id, true)
+            + additional_storage_cost;
+        let amount = parked.as_ref().map(|parked| parked.amount).unwrap_or(0);
+        if amount < storage_cost {
+            return Err(AccountsError::InsufficientNear {
+                required: storage_cost,
+                available: amount,
+            });
+        }
+        let parked = parked.unwrap();
+        let sponsor_id = Some(&parked.parker_id).filter(|id| *id != account_id);
+        self.internal_register_account(account_id, amount, sponsor_id)?;
+        self.parked_deposits.remove(account_id);
+        events::emit_register(account_id, &parked.parker_id, amount);
+        Ok(())
+    }
+
+    /// Register an account with Near lent from the storage reserve
+    ///
+    /// The loan covers the account's storage and `additional_storage_cost`. It is recorded as the account's
+    /// storage debt, which is repaid on the account's next deposit or when it unregisters
+    pub fn try_register_from_storage_reserve(
+        &mut self,
+        account_id: &AccountId,
+        additional_storage_cost: Balance,
+    ) -> AccountsResult<()> {
+        let storage_cost =
+            self.get_storage_cost(Some(account_id.clone()), None, true) + additional_storage_cost;
+        if self.storage_reserve < storage_cost {
+            return Err(AccountsError::InsufficientNear {
+                required: storage_cost,
+                available: self.storage_reserve,
+            });
+        }
+        let mut account = self.internal_register_account(account_id, storage_cost, None)?;
+        self.storage_reserve -= storage_cost;
+        account.storage_debt = storage_cost;
+        self.accounts.insert(account_id, &account);
+        events::emit_register(account_id, &env::current_account_id(), storage_cost);
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use near_sdk::{env, AccountId, Balance, Promise};
+
+use crate::{
+    events, Account, AccountDeposits, AccountInfoTrait, Accounts, AccountsError, AccountsResult,
+    UnwrapOrPanic,
+};
+
+/// Who pays for the storage of a change to an account
+#[derive(Clone, Debug, PartialEq)]
+pub enum StoragePayer {
+    /// The account which is changed
+    Account,
+    /// Another registered account, i.e. the sender of a transfer
+    Sponsor(AccountId),
+    /// The contract, from its storage reserve
+    Contract,
+}
+
+impl<Info: AccountInfoTrait> Accounts<Info> {
+    /// Same as `check_storage` but the storage increase is paid for by `payer`
+    ///
+    /// A sponsor's Near is moved into the account and recorded as sponsored, so that it goes back to
+    /// the sponsor once the account is unregistered. The contract pays from the storage reserve and the amount
+    /// is recorded as the account's storage debt.
+    ///
+    /// The payer falls back to the account itself, rather than failing, if the sponsor is the account,
+    /// is not registered, or if the account already has another sponsor (an account has a single sponsor).
+    /// The same goes for the contract if the storage reserve is too small
+    pub fn check_storage_paid_by<F, T: Sized>(
+        &mut self,
+        account: &mut Account<Info>,
+        account_id: &AccountId,
+        payer: &StoragePayer,
+        closure: F,
+    ) -> T
+    where
+        F: FnOnce(&mut Accounts<Info>, &mut Account<Info>) -> T,
+    {
+        self.try_check_storage_paid_by(account, account_id, payer, closure).unwrap_or_panic()
+    }
+
+    /// Same as `check_storage_paid_by` but returns an error if neither the payer nor the account can pay
+    ///
+    /// Changes made by the closure are not reverted on error, so the caller is expected to panic
+    pub fn try_check_storage_paid_by<F, T: Sized>(
+        &mut self,
+        account: &mut Account<Info>,
+        account_id: &AccountId,
+        payer: &StoragePayer,
+        closure: F,
+    ) -> AccountsResult<T>
+    where
+        F: FnOnce(&mut Accounts<Info>, &mut Account<Info>) -> T,
+    {
+        match payer {
+            StoragePayer::Sponsor(sponsor_id)
+                if self.can_sponsor_storage(account, account_id, sponsor_id) =>
+            {
+                self.check_storage_sponsored(account, account_id, sponsor_id, closure)
+            }
+            StoragePayer::Contract => self.check_storage_from_reserve(account, account_id, closure),
+            _ => self.try_check_storage(account, account_id, closure),
+        }
+    }
+
+    /// Check that `payer` can pay for each of the storage costs of the accounts, without changing any state
+    ///
+    /// The costs are paid in order with the fallbacks of `check_storage_paid_by`. A payer paying for several
+    /// accounts has to cover their sum, i.e. a sender sponsoring all recipients of a batch of transfers
+    pub fn check_storage_costs_payable(
+        &self,
+        storage_costs: &[(AccountId, Balance)],
+        payer: &StoragePayer,
+    ) -> AccountsResult<()> {
+        let mut storage_reserve = self.storage_reserve;
+        let mut amounts_charged: HashMap<AccountId, Balance> = HashMap::new();
+        for (account_id, storage_cost) in storage_costs.iter() {
+            let account = self.try_get_account(account_id)?;
+            let (payer_id, storage_cost) = match payer {
+                StoragePayer::Sponsor(sponsor_id)
+                    if self.can_sponsor_storage(&account, account_id, sponsor_id) =>
+                {
+                    // A new sponsor is recorded in the account, which is paid for by the sponsor as well
+                    let sponsor_bytes = match account.sponsor_id {
+                        Some(_) => 0,
+                        None => sponsor_id.len() as u128 + 4,
+                    };
+                    (sponsor_id, storage_cost + sponsor_bytes * env::storage_byte_cost())
+                }
+                StoragePayer::Contract if storage_reserve >= *storage_cost => {
+                    storage_reserve -= storage_cost;
+                    continue;
+                }
+                _ => (account_id, *storage_cost),
+            };
+            let amount_charged = amounts_charged.entry(payer_id.clone()).or_insert(0);
+            *amount_charged += storage_cost;
+            let available = self.try_get_account(payer_id)?.get_available_near();
+            if available < *amount_charged {
+                return Err(AccountsError::InsufficientNear {
+                    required: *amount_charged,
+                    available,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the sponsor can pay for the account's storage, otherwise the account pays for itself
+    fn can_sponsor_storage(
+        &self,
+        account: &Account<Info>,
+        account_id: &AccountId,
+        sponsor_id: &AccountId,
+    ) -> bool {
+        sponsor_id != account_id
+            && self.accounts.get(sponsor_id).is_some()
+            && account.can_be_sponsored_by(sponsor_id)
+    }
+
+    fn check_storage_sponsored<F, T: Sized>(
+        &mut self,
+        account: &mut Account<Info>,
+        account_id: &AccountId,
+        sponsor_id: &AccountId,
+        closure: F,
+    ) -> AccountsResult<T>
+    where
+        F: FnOnce(&mut Accounts<Info>, &mut Account<Info>) -> T,
+    {
+        let storage_start = env::storage_usage();
+        // Record the sponsor up front so that the bytes of the sponsor id are paid for as well
+        let is_new_sponsor = account.sponsor_id.is_none();
+        account.add_sponsored(sponsor_id, 0);
+        let ret = closure(self, account);
+        self.accounts.insert(account_id, account);
+        let mut storage_end = env::storage_usage();
+        if storage_end <= storage_start && is_new_sponsor {
+            account.remove_sponsored(0);
+            self.accounts.insert(account_id, account);
+            storage_end = env::storage_usage();
+        }
+
+        if storage_end > storage_start {
+            let storage_cost = (storage_end - storage_start) as u128 * env::storage_byte_cost();
+            let mut sponsor = self.try_get_account(sponsor_id)?;
+            if let Err(err) = sponsor.charge_near(storage_cost) {
+                if is_new_sponsor {
+                    account.remove_sponsored(0);
+                }
+                return Err(err);
+            }
+            self.accounts.insert(sponsor_id, &sponsor);
+            account.near_amount += storage_cost;
+            account.add_sponsored(sponsor_id, storage_cost);
+            if is_new_sponsor {
+                account.sponsor_is_storage_payer = true;
+            }
+        }
+        self.try_charge_storage(account, storage_start, storage_end)?;
+        self.accounts.insert(account_id, account);
+        Ok(ret)
+    }
+
+    fn check_storage_from_reserve<F, T: Sized>(
+        &mut self,
+        account: &mut Account<Info>,
+        account_id: &AccountId,
+        closure: F,
+    ) -> AccountsResult<T>
+    where
+        F: FnOnce(&mut Accounts<Info>, &mut Account<Info>) -> T,
+    {
+        let storage_start = env::storage_usage();
+        let ret = closure(self, account);
+        self.accounts.insert(account_id, account);
+        let storage_end = env::storage_usage();
+
+        if storage_end > storage_start {
+            let storage_cost = (storage_end - storage_start) as u128 * env::storage_byte_cost();
+            if self.storage_reserve >= storage_cost {
+                self.storage_reserve -= storage_cost;
+                account.near_amount += storage_cost;
+                account.storage_debt += storage_cost;
+            }
+        }
+        self.try_charge_storage(account, storage_start, storage_end)?;
+        self.accounts.insert(account_id, account);
+        Ok(ret)
+    }
+
+    /// Let `sponsor_id` take the place of a sponsor which only paid for storage as the sender of transfers
+    ///
+    /// The storage payer's Near goes back to its account, which requires the account to have enough free Near
+    /// to cover it. Otherwise the storage payer stays the sponsor
+    pub(crate) fn release_storage_payer(
+        &mut self,
+        account: &mut Account<Info>,
+        sponsor_id: &AccountId,
+    ) {
+        if !account.sponsor_is_storage_payer
+            || account.can_be_sponsored_by(sponsor_id)
+            || account.get_available_near() < account.sponsored_amount
+        {
+            return;
+        }
+        let payer_id = account.sponsor_id.clone().unwrap();
+        let amount = account.sponsored_amount;
+        account.near_amount -= amount;
+        account.remove_sponsored(amount);
+        match self.accounts.get(&payer_id) {
+            Some(mut payer) => {
+                payer.near_amount += amount;
+                self.accounts.insert(&payer_id, &payer);
+            }
+            None => {
+                events::emit_refund(&payer_id, amount);
+                Promise::new(payer_id).transfer(amount);
+            }
+        }
+    }
+
+    /// Same as `insert_account_check_storage` but the storage increase is paid for by `payer`
+    pub fn insert_account_check_storage_paid_by(
+        &mut self,
+        account_id: &AccountId,
+        account: &mut Account<Info>,
+        payer: &StoragePayer,
+    ) -> Option<Account<Info>> {
+        self.check_storage_paid_by(account, account_id, payer, |accounts, account| {
+            accounts.accounts.insert(account_id, account)
+        })
+    }
+}
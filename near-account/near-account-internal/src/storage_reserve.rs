@@ -0,0 +1,132 @@
+use near_sdk::{env, Balance, Promise, StorageUsage};
+
+use crate::{
+    assert_one_yocto, Account, AccountDeposits, AccountInfoTrait, Accounts, AccountsError,
+    AccountsResult, UnwrapOrPanic,
+};
+
+/// The contract's storage reserve, funded by the owner
+///
+/// When a callback changes an account which does not have enough free Near for the storage,
+/// the shortfall is lent from the reserve and recorded as the account's storage debt.
+/// The debt is repaid when storage is freed, on the account's next deposit, or when it unregisters.
+/// The reserve also pays for contract level state, see `try_pay_from_storage_reserve`
+impl<Info: AccountInfoTrait> Accounts<Info> {
+    /// Add the attached deposit to the storage reserve
+    pub fn fund_storage_reserve(&mut self) -> Balance {
+        self.assert_owner();
+        self.storage_reserve += env::attached_deposit();
+        self.storage_reserve
+    }
+
+    /// Withdraw from the storage reserve to the owner, withdraws everything if `amount` is None
+    pub fn withdraw_storage_reserve(&mut self, amount: Option<Balance>) -> Balance {
+        assert_one_yocto();
+        self.assert_owner();
+        let amount = amount.unwrap_or(self.storage_reserve);
+        if amount > self.storage_reserve {
+            Err(AccountsError::InsufficientNear {
+                required: amount,
+                available: self.storage_reserve,
+            })
+            .unwrap_or_panic()
+        }
+        self.storage_reserve -= amount;
+        Promise::new(self.owner_id.clone()).transfer(amount);
+        self.storage_reserve
+    }
+
+    /// Call the closure and pay for the change in storage from the storage reserve
+    ///
+    /// Meant for state of the contract rather than of an account, i.e. the configuration of a plugin.
+    /// Freed storage is returned to the reserve. Changes made by the closure are not reverted on error,
+    /// so the caller is expected to panic
+    pub fn try_pay_from_storage_reserve<F, T: Sized>(&mut self, closure: F) -> AccountsResult<T>
+    where
+        F: FnOnce(&mut Accounts<Info>) -> T,
+    {
+        let storage_start = env::storage_usage();
+        let ret = closure(self);
+        let storage_end = env::storage_usage();
+        if storage_end > storage_start {
+            let storage_cost = (storage_end - storage_start) as u128 * env::storage_byte_cost();
+            if self.storage_reserve < storage_cost {
+                return Err(AccountsError::InsufficientNear {
+                    required: storage_cost,
+                    available: self.storage_reserve,
+                });
+            }
+            self.storage_reserve -= storage_cost;
+        } else {
+            self.storage_reserve +=
+                (storage_start - storage_end) as u128 * env::storage_byte_cost();
+        }
+        Ok(ret)
+    }
+
+    /// Charge the account for the change in storage from `storage_start` to `storage_end`
+    pub(crate) fn try_charge_storage(
+        &mut self,
+        account: &mut Account<Info>,
+        storage_start: StorageUsage,
+        storage_end: StorageUsage,
+    ) -> AccountsResult<()> {
+        if storage_end > storage_start {
+            let storage_increase = storage_end - storage_start;
+            let storage_cost = storage_increase as u128 * env::storage_byte_cost();
+            let free_near = account.get_available_near();
+            if free_near < storage_cost {
+                self.lend_from_storage_reserve(account, storage_cost, free_near)?;
+            }
+            account.near_used_for_storage += storage_cost;
+            account.storage_usage += storage_increase;
+        } else if storage_end < storage_start {
+            let storage_decrease = storage_start - storage_end;
+            let storage_refund = storage_decrease as u128 * env::storage_byte_cost();
+            account.near_used_for_storage =
+                account.near_used_for_storage.saturating_sub(storage_refund);
+            account.storage_usage =
+                account.storage_usage.saturating_sub(storage_decrease);
+            self.repay_storage_debt(account, storage_refund);
+        }
+        Ok(())
+    }
+
+    /// Lend the shortfall between `storage_cost` and the account's `free_near` from the storage reserve,
+    /// only allowed within the contract's callbacks
+    fn lend_from_storage_reserve(
+        &mut self,
+        account: &mut Account<Info>,
+        storage_cost: Balance,
+        free_near: Balance,
+    ) -> AccountsResult<()> {
+        let amount = storage_cost - free_near;
+        let is_callback = env::predecessor_account_id() == env::current_account_id();
+        if !is_callback || self.storage_reserve < amount {
+            return Err(AccountsError::InsufficientNear {
+                required: storage_cost,
+                available: free_near,
+            });
+        }
+        debug_log!("Lending {} from the storage reserve", amount);
+        self.storage_reserve -= amount;
+        account.near_amount += amount;
+        account.storage_debt += amount;
+        Ok(())
+    }
+
+    /// Repay the account's storage debt with up to `amount` of its Near, returns the amount repaid
+    pub(crate) fn repay_storage_debt(
+        &mut self,
+        account: &mut Account<Info>,
+        amount: Balance,
+    ) -> Balance {
+        let amount_repaid = account.storage_debt.min(amount).min(account.near_amount);
+        if amount_repaid != 0 {
+            account.storage_debt -= amount_repaid;
+            account.near_amount -= amount_repaid;
+            self.storage_reserve += amount_repaid;
+        }
+        amount_repaid
+    }
+}
@@ -1,8 +1,132 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, DeriveInput, ItemStruct};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, DeriveInput, Lit, Meta, NestedMeta};
 
-#[proc_macro_derive(NearAccounts)]
+/// Get the arguments of all `#[near_accounts(...)]` attributes
+fn get_near_accounts_args(attrs: &[syn::Attribute]) -> Result<Vec<NestedMeta>, syn::Error> {
+    let mut args = vec![];
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("near_accounts")) {
+        match attr.parse_meta()? {
+            Meta::List(list) => args.extend(list.nested),
+            meta => return Err(syn::Error::new_spanned(meta, "Expected #[near_accounts(...)]")),
+        }
+    }
+    Ok(args)
+}
+
+/// Get the string value of a `name = "..."` argument
+fn get_str_arg(arg: &NestedMeta, name: &str) -> Result<Option<syn::LitStr>, syn::Error> {
+    match arg {
+        NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident(name) => match &nv.lit {
+            Lit::Str(lit) => Ok(Some(lit.clone())),
+            lit => Err(syn::Error::new_spanned(lit, format!("Expected a string for {}", name))),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Get the storage prefix set with `#[near_accounts(prefix = "...")]` on a field
+fn get_prefix_attr(field: &syn::Field) -> Result<Option<syn::LitStr>, syn::Error> {
+    let mut prefix = None;
+    for arg in get_near_accounts_args(&field.attrs)? {
+        match get_str_arg(&arg, "prefix")? {
+            Some(lit) => prefix = Some(lit),
+            None => {
+                return Err(syn::Error::new_spanned(arg, "Unknown near_accounts field attribute"))
+            }
+        }
+    }
+    Ok(prefix)
+}
+
+/// Check if the type of a field is `Accounts<_>`
+fn is_accounts_type(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident == "Accounts")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Arguments set with `#[near_accounts(...)]` on the struct
+#[derive(Default)]
+struct StructArgs {
+    field: Option<syn::LitStr>,
+    storage_management: Option<syn::LitStr>,
+    on_forfeit: Option<syn::LitStr>,
+}
+
+fn get_struct_args(input: &DeriveInput) -> Result<StructArgs, syn::Error> {
+    let mut args = StructArgs::default();
+    for arg in get_near_accounts_args(&input.attrs)? {
+        if let Some(lit) = get_str_arg(&arg, "field")? {
+            args.field = Some(lit);
+        } else if let Some(lit) = get_str_arg(&arg, "storage_management")? {
+            args.storage_management = Some(lit);
+        } else if let Some(lit) = get_str_arg(&arg, "on_forfeit")? {
+            args.on_forfeit = Some(lit);
+        } else {
+            return Err(syn::Error::new_spanned(arg, "Unknown near_accounts struct attribute"));
+        }
+    }
+    Ok(args)
+}
+
+/// Find the accounts field, either set with `#[near_accounts(field = "...")]` on the struct
+/// or the only field of type `Accounts<_>`
+fn get_accounts_field(
+    input: &DeriveInput,
+    struct_: &syn::DataStruct,
+    field_name: Option<syn::LitStr>,
+) -> Result<syn::Ident, syn::Error> {
+    let named_fields = struct_.fields.iter().filter_map(|f| f.ident.as_ref().map(|i| (i, f)));
+    if let Some(lit) = field_name {
+        return named_fields
+            .map(|(ident, _)| ident)
+            .find(|ident| **ident == lit.value())
+            .cloned()
+            .ok_or_else(|| {
+                syn::Error::new_spanned(
+                    &lit,
+                    format!("No field named {} found in {}", lit.value(), input.ident),
+                )
+            });
+    }
+
+    let accounts_fields: Vec<&syn::Ident> =
+        named_fields.filter(|(_, f)| is_accounts_type(&f.ty)).map(|(ident, _)| ident).collect();
+    match accounts_fields.len() {
+        1 => Ok(accounts_fields[0].clone()),
+        0 => Err(syn::Error::new_spanned(
+            &input.ident,
+            "#[derive(NearAccounts)] requires a field of type Accounts<_>",
+        )),
+        _ => Err(syn::Error::new_spanned(
+            &input.ident,
+            format!(
+                "Found multiple fields of type Accounts<_> ({}), select one with #[near_accounts(field = \"...\")]",
+                accounts_fields.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ")
+            ),
+        )),
+    }
+}
+
+/// Generate the storage management methods for a contract's `Accounts`
+///
+/// Struct attributes:
+/// * `#[near_accounts(field = "...")]` - the accounts field, needed if there are multiple `Accounts<_>` fields
+/// * `#[near_accounts(storage_management = "prefixed" | "nep145")]` - use the `accounts_` prefixed method names (default)
+///   or the standard NEP-145 `StorageManagement` method names
+/// * `#[near_accounts(on_forfeit = "...")]` - a function `fn(&mut Accounts<Info>, &AccountId, &mut Account<Info>)` which
+///   receives the balances forfeited by a forced unregister
+///
+/// Field attributes:
+/// * `#[near_accounts(prefix = "...")]` - the storage prefix used by the generated `new_<field>` constructor
+#[proc_macro_derive(NearAccounts, attributes(near_accounts))]
 pub fn near_accounts(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let input_struct = input.clone();
@@ -15,8 +139,163 @@ pub fn near_accounts(input: TokenStream) -> TokenStream {
     let (impl_generics, ty_generics, where_clause) = input_struct.generics.split_for_impl();
     let struct_name = &input_struct.ident;
 
+    let struct_args = match get_struct_args(&input_struct) {
+        Ok(args) => args,
+        Err(e) => return TokenStream::from(e.to_compile_error()),
+    };
+
+    let accounts = match get_accounts_field(&input_struct, &struct_, struct_args.field) {
+        Ok(field) => field,
+        Err(e) => return TokenStream::from(e.to_compile_error()),
+    };
+
+    // Constructors for the fields which have a custom storage prefix
+    let mut constructors = vec![];
+    for field in struct_.fields.iter() {
+        let prefix = match get_prefix_attr(field) {
+            Ok(Some(prefix)) => prefix,
+            Ok(None) => continue,
+            Err(e) => return TokenStream::from(e.to_compile_error()),
+        };
+        let field_name = match &field.ident {
+            Some(ident) => ident,
+            None => continue,
+        };
+        let field_ty = &field.ty;
+        let constructor_name = format_ident!("new_{}", field_name);
+        constructors.push(quote! {
+            /// Create the account registry using the storage prefix from `#[near_accounts(prefix = "...")]`
+            pub fn #constructor_name() -> #field_ty {
+                <#field_ty>::new_with_prefix(#prefix.as_bytes().to_vec())
+            }
+        });
+    }
+
+    // Forfeiting balances on a forced unregister requires the callback set with `#[near_accounts(on_forfeit = "...")]`
+    let unregister = match struct_args.on_forfeit {
+        Some(on_forfeit) => {
+            let on_forfeit: syn::Path = match on_forfeit.parse() {
+                Ok(path) => path,
+                Err(e) => return TokenStream::from(e.to_compile_error()),
+            };
+            quote! { self.#accounts.storage_unregister_with_forfeit(force, #on_forfeit) }
+        }
+        None => quote! {
+            near_contract_standards::storage_management::StorageManagement::storage_unregister(&mut self.#accounts, force)
+        },
+    };
+
+    let storage_management = match struct_args.storage_management {
+        None => storage_management_prefixed(
+            struct_name,
+            &ty_generics,
+            where_clause,
+            &accounts,
+            &unregister,
+        ),
+        Some(mode) if mode.value() == "prefixed" => storage_management_prefixed(
+            struct_name,
+            &ty_generics,
+            where_clause,
+            &accounts,
+            &unregister,
+        ),
+        Some(mode) if mode.value() == "nep145" => storage_management_nep145(
+            struct_name,
+            &ty_generics,
+            where_clause,
+            &accounts,
+            &unregister,
+        ),
+        Some(mode) => {
+            return TokenStream::from(
+                syn::Error::new_spanned(
+                    mode,
+                    "Expected storage_management to be either \"prefixed\" or \"nep145\"",
+                )
+                .to_compile_error(),
+            )
+        }
+    };
+
     let stream = quote! {
 
+        impl #impl_generics #struct_name #ty_generics #where_clause {
+            #(#constructors)*
+        }
+
+        #storage_management
+
+        #[near_sdk::near_bindgen]
+        impl #struct_name #ty_generics #where_clause {
+            pub fn accounts_storage_usage_of(&self, account_id: near_sdk::json_types::ValidAccountId) -> Option<near_account::AccountStorageUsage> {
+                self.#accounts.storage_usage_of(&account_id.into())
+            }
+
+            pub fn accounts_count(&self) -> near_sdk::json_types::U64 {
+                self.#accounts.accounts_count().into()
+            }
+
+            pub fn accounts_list(&self, from_index: Option<near_sdk::json_types::U64>, limit: Option<near_sdk::json_types::U64>) -> Vec<near_account::AccountStorageBalance> {
+                self.#accounts.accounts_list(from_index.map(|index| index.into()), limit.map(|limit| limit.into()))
+            }
+
+            #[payable]
+            pub fn accounts_set_max_storage_balance(&mut self, max: Option<near_sdk::json_types::U128>) {
+                self.#accounts.set_max_storage_bal(max.map(|max| max.into()))
+            }
+
+            #[payable]
+            pub fn accounts_set_storage_from_attached_deposit(&mut self, enabled: bool) {
+                self.#accounts.set_storage_from_attached_deposit(enabled)
+            }
+
+            #[payable]
+            pub fn accounts_fund_storage_reserve(&mut self) -> near_sdk::json_types::U128 {
+                self.#accounts.fund_storage_reserve().into()
+            }
+
+            #[payable]
+            pub fn accounts_withdraw_storage_reserve(&mut self, amount: Option<near_sdk::json_types::U128>) -> near_sdk::json_types::U128 {
+                self.#accounts.withdraw_storage_reserve(amount.map(|amount| amount.into())).into()
+            }
+
+            pub fn accounts_storage_reserve(&self) -> near_sdk::json_types::U128 {
+                self.#accounts.storage_reserve.into()
+            }
+
+            #[payable]
+            pub fn accounts_park_storage_deposit(&mut self, account_id: near_sdk::json_types::ValidAccountId) -> near_sdk::json_types::U128 {
+                self.#accounts.park_storage_deposit(&account_id.into()).into()
+            }
+
+            #[payable]
+            pub fn accounts_withdraw_parked_storage_deposit(&mut self, account_id: near_sdk::json_types::ValidAccountId) -> near_sdk::json_types::U128 {
+                self.#accounts.withdraw_parked_storage_deposit(&account_id.into()).into()
+            }
+
+            pub fn accounts_parked_storage_deposit(&self, account_id: near_sdk::json_types::ValidAccountId) -> near_sdk::json_types::U128 {
+                self.#accounts.get_parked_storage_deposit(&account_id.into()).into()
+            }
+
+            #[payable]
+            pub fn accounts_set_owner(&mut self, owner_id: near_sdk::json_types::ValidAccountId) {
+                self.#accounts.set_owner(owner_id.into())
+            }
+        }
+    };
+    TokenStream::from(stream)
+}
+
+/// Storage management under the `accounts_` prefix so that it does not clash with a token's own storage management
+fn storage_management_prefixed(
+    struct_name: &syn::Ident,
+    ty_generics: &syn::TypeGenerics,
+    where_clause: Option<&syn::WhereClause>,
+    accounts: &syn::Ident,
+    unregister: &proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    quote! {
         #[near_sdk::near_bindgen]
         impl #struct_name #ty_generics #where_clause {
 
@@ -26,27 +305,68 @@ pub fn near_accounts(input: TokenStream) -> TokenStream {
                 account_id: Option<near_sdk::json_types::ValidAccountId>,
                 registration_only: Option<bool>,
             ) -> near_contract_standards::storage_management::StorageBalance {
-                self.accounts.storage_deposit(account_id, registration_only)
+                near_contract_standards::storage_management::StorageManagement::storage_deposit(&mut self.#accounts, account_id, registration_only)
             }
 
             #[payable]
             pub fn accounts_storage_withdraw(&mut self, amount: Option<near_sdk::json_types::U128>) -> near_contract_standards::storage_management::StorageBalance {
-                self.accounts.storage_withdraw(amount)
+                near_contract_standards::storage_management::StorageManagement::storage_withdraw(&mut self.#accounts, amount)
             }
 
             #[payable]
             pub fn accounts_storage_unregister(&mut self, force: Option<bool>) -> bool {
-                self.accounts.storage_unregister(force)
+                #unregister
             }
 
             pub fn accounts_storage_balance_bounds(&self) -> near_contract_standards::storage_management::StorageBalanceBounds {
-                self.accounts.storage_balance_bounds()
+                near_contract_standards::storage_management::StorageManagement::storage_balance_bounds(&self.#accounts)
             }
 
             pub fn accounts_storage_balance_of(&self, account_id: near_sdk::json_types::ValidAccountId) -> Option<near_contract_standards::storage_management::StorageBalance> {
-                self.accounts.storage_balance_of(account_id)
+                near_contract_standards::storage_management::StorageManagement::storage_balance_of(&self.#accounts, account_id)
             }
         }
-    };
-    TokenStream::from(stream)
+    }
+}
+
+/// Storage management with the standard NEP-145 method names
+fn storage_management_nep145(
+    struct_name: &syn::Ident,
+    ty_generics: &syn::TypeGenerics,
+    where_clause: Option<&syn::WhereClause>,
+    accounts: &syn::Ident,
+    unregister: &proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    quote! {
+        #[near_sdk::near_bindgen]
+        impl near_contract_standards::storage_management::StorageManagement for #struct_name #ty_generics #where_clause {
+
+            #[payable]
+            fn storage_deposit(
+                &mut self,
+                account_id: Option<near_sdk::json_types::ValidAccountId>,
+                registration_only: Option<bool>,
+            ) -> near_contract_standards::storage_management::StorageBalance {
+                near_contract_standards::storage_management::StorageManagement::storage_deposit(&mut self.#accounts, account_id, registration_only)
+            }
+
+            #[payable]
+            fn storage_withdraw(&mut self, amount: Option<near_sdk::json_types::U128>) -> near_contract_standards::storage_management::StorageBalance {
+                near_contract_standards::storage_management::StorageManagement::storage_withdraw(&mut self.#accounts, amount)
+            }
+
+            #[payable]
+            fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+                #unregister
+            }
+
+            fn storage_balance_bounds(&self) -> near_contract_standards::storage_management::StorageBalanceBounds {
+                near_contract_standards::storage_management::StorageManagement::storage_balance_bounds(&self.#accounts)
+            }
+
+            fn storage_balance_of(&self, account_id: near_sdk::json_types::ValidAccountId) -> Option<near_contract_standards::storage_management::StorageBalance> {
+                near_contract_standards::storage_management::StorageManagement::storage_balance_of(&self.#accounts, account_id)
+            }
+        }
+    }
 }
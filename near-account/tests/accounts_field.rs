@@ -0,0 +1,67 @@
+use near_account::{AccountInfoTrait, Accounts, NearAccounts, NewInfo};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::test_utils::{accounts, VMContextBuilder};
+use near_sdk::{near_bindgen, testing_env, AccountId, MockedBlockchain};
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Info {}
+
+impl NewInfo for Info {
+    fn default_from_account_id(_account_id: AccountId) -> Self {
+        Self {}
+    }
+}
+
+impl AccountInfoTrait for Info {}
+
+/// The only `Accounts<_>` field is found whatever its name
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, NearAccounts)]
+pub struct RenamedContract {
+    pub registry: Accounts<Info>,
+}
+
+/// The accounts field is selected among several with `field = "..."`
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, NearAccounts)]
+#[near_accounts(field = "users")]
+pub struct SelectedContract {
+    #[near_accounts(prefix = "admins")]
+    pub admins: Accounts<Info>,
+    #[near_accounts(prefix = "users")]
+    pub users: Accounts<Info>,
+}
+
+fn get_context() -> VMContextBuilder {
+    let mut context = VMContextBuilder::new();
+    context.current_account_id(accounts(0)).predecessor_account_id(accounts(1));
+    testing_env!(context.build());
+    context
+}
+
+#[test]
+fn test_renamed_accounts_field() {
+    let mut context = get_context();
+    let mut contract = RenamedContract { registry: Accounts::new() };
+    let min = contract.accounts_storage_balance_bounds().min.0;
+
+    testing_env!(context.attached_deposit(min).build());
+    contract.accounts_storage_deposit(None, None);
+    assert_eq!(contract.accounts_count().0, 1);
+    assert!(contract.registry.get_account(&accounts(1).into()).is_some());
+}
+
+#[test]
+fn test_selected_accounts_field() {
+    let mut context = get_context();
+    let mut contract = SelectedContract {
+        admins: SelectedContract::new_admins(),
+        users: SelectedContract::new_users(),
+    };
+    let min = contract.accounts_storage_balance_bounds().min.0;
+
+    testing_env!(context.attached_deposit(min).build());
+    contract.accounts_storage_deposit(None, None);
+    assert!(contract.users.get_account(&accounts(1).into()).is_some());
+    assert!(contract.admins.get_account(&accounts(1).into()).is_none());
+}
@@ -0,0 +1,5 @@
+#[test]
+fn test_derive_errors() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
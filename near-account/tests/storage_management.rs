@@ -0,0 +1,92 @@
+use near_account::{Account, AccountInfoTrait, Accounts, NearAccounts, NewInfo};
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::test_utils::{accounts, VMContextBuilder};
+use near_sdk::{env, near_bindgen, testing_env, AccountId, MockedBlockchain};
+
+const FORFEITED_KEY: &[u8] = b"forfeited";
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Info {
+    pub points: u64,
+}
+
+impl NewInfo for Info {
+    fn default_from_account_id(_account_id: AccountId) -> Self {
+        Self { points: 0 }
+    }
+}
+
+impl AccountInfoTrait for Info {
+    fn can_unregister(&self) -> bool {
+        self.points == 0
+    }
+}
+
+/// Keep the forfeited points of the last forced unregister
+fn forfeit_points(
+    _accounts: &mut Accounts<Info>,
+    _account_id: &AccountId,
+    account: &mut Account<Info>,
+) {
+    env::storage_write(FORFEITED_KEY, &account.info.points.to_le_bytes());
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, NearAccounts)]
+#[near_accounts(storage_management = "nep145", on_forfeit = "forfeit_points")]
+pub struct Contract {
+    pub accounts: Accounts<Info>,
+}
+
+fn setup() -> (Contract, VMContextBuilder, u128) {
+    let mut context = VMContextBuilder::new();
+    context.current_account_id(accounts(0)).predecessor_account_id(accounts(1));
+    testing_env!(context.build());
+    let mut contract = Contract { accounts: Accounts::new() };
+    let min = contract.storage_balance_bounds().min.0;
+
+    testing_env!(context.attached_deposit(min * 2).build());
+    contract.storage_deposit(None, None);
+    (contract, context, min)
+}
+
+#[test]
+fn test_nep145_methods() {
+    let (mut contract, mut context, min) = setup();
+    let bal = contract.storage_balance_of(accounts(1)).unwrap();
+    assert_eq!(bal.total.0, min * 2);
+
+    testing_env!(context.attached_deposit(1).build());
+    let bal = contract.storage_withdraw(None);
+    assert_eq!(bal.available.0, 0);
+    assert!(contract.storage_unregister(None));
+    assert!(contract.storage_balance_of(accounts(1)).is_none());
+}
+
+#[test]
+fn test_forced_unregister_forfeits() {
+    let (mut contract, mut context, _min) = setup();
+    let account_id: AccountId = accounts(1).into();
+    let mut account = contract.accounts.get_account_checked(&account_id);
+    account.info.points = 5;
+    contract.accounts.insert_account_unchecked(&account_id, &account);
+
+    testing_env!(context.attached_deposit(1).build());
+    assert!(contract.storage_unregister(Some(true)));
+    assert_eq!(env::storage_read(FORFEITED_KEY), Some(5u64.to_le_bytes().to_vec()));
+    assert!(contract.storage_balance_of(accounts(1)).is_none());
+}
+
+#[test]
+#[should_panic(expected = "ERR_HOLDS_BALANCES")]
+fn test_unregister_without_force() {
+    let (mut contract, mut context, _min) = setup();
+    let account_id: AccountId = accounts(1).into();
+    let mut account = contract.accounts.get_account_checked(&account_id);
+    account.info.points = 5;
+    contract.accounts.insert_account_unchecked(&account_id, &account);
+
+    testing_env!(context.attached_deposit(1).build());
+    contract.storage_unregister(None);
+}
@@ -0,0 +1,23 @@
+use near_account::{AccountInfoTrait, Accounts, NearAccounts, NewInfo};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{near_bindgen, AccountId};
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Info {}
+
+impl NewInfo for Info {
+    fn default_from_account_id(_account_id: AccountId) -> Self {
+        Self {}
+    }
+}
+
+impl AccountInfoTrait for Info {}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, NearAccounts)]
+pub struct Contract {
+    pub admins: Accounts<Info>,
+    pub users: Accounts<Info>,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use near_account::NearAccounts;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::near_bindgen;
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, NearAccounts)]
+pub struct Contract {
+    pub owner: String,
+}
+
+fn main() {}
@@ -0,0 +1,153 @@
+use near_account::{assert_one_yocto, Accounts, AccountsError, UnwrapOrPanic};
+use near_sdk::{env, AccountId, Balance};
+
+use crate::{
+    core_impl::{increase_balance_paid_by, subtract_balance, AccountInfoTrait},
+    events,
+    storage_payer::get_storage_payer,
+    AllowanceInfo,
+};
+
+/// Allow `spender_id` to spend up to `amount` of the caller's token, the caller pays for the allowance's storage
+pub fn approve<Info: AccountInfoTrait + AllowanceInfo>(
+    accounts: &mut Accounts<Info>,
+    spender_id: &AccountId,
+    token_id: &AccountId,
+    amount: Balance,
+) {
+    assert_one_yocto();
+    let owner_id = env::predecessor_account_id();
+    if spender_id == &owner_id {
+        Err(AccountsError::SelfApproval { account_id: owner_id.clone() }).unwrap_or_panic()
+    }
+    let mut account = accounts.get_account_checked(&owner_id);
+    accounts.check_storage(&mut account, &owner_id, |_, account| {
+        account.info.set_allowance(spender_id, token_id, amount)
+    });
+    events::emit_approve(&owner_id, spender_id, token_id, amount);
+}
+
+/// Get how much of the owner's token the spender can spend, 0 if the owner is not registered
+pub fn get_allowance<Info: AccountInfoTrait + AllowanceInfo>(
+    accounts: &Accounts<Info>,
+    owner_id: &AccountId,
+    spender_id: &AccountId,
+    token_id: &AccountId,
+) -> Balance {
+    accounts
+        .get_account(owner_id)
+        .map(|account| account.info.get_allowance(spender_id, token_id))
+        .unwrap_or(0)
+}
+
+/// Transfer the owner's token to `recipient` and decrease the caller's allowance by `amount`
+///
+/// The storage for a new balance of the recipient is paid according to the owner's storage payer policy
+pub fn transfer_from<Info: AccountInfoTrait + AllowanceInfo>(
+    accounts: &mut Accounts<Info>,
+    owner_id: &AccountId,
+    recipient: &AccountId,
+    token_id: &AccountId,
+    amount: Balance,
+) {
+    assert_one_yocto();
+    let spender_id = env::predecessor_account_id();
+    let mut account = accounts.get_account_checked(owner_id);
+    let allowance = account.info.get_allowance(&spender_id, token_id);
+    if allowance < amount {
+        Err(AccountsError::InsufficientAllowance {
+            owner_id: owner_id.clone(),
+            spender_id: spender_id.clone(),
+            token_id: token_id.clone(),
+            allowance,
+            amount,
+        })
+        .unwrap_or_panic()
+    }
+    accounts.check_storage(&mut account, owner_id, |_, account| {
+        account.info.set_allowance(&spender_id, token_id, allowance - amount)
+    });
+
+    subtract_balance(accounts, owner_id, token_id, amount);
+    let payer = get_storage_payer(accounts, owner_id);
+    increase_balance_paid_by(accounts, recipient, token_id, amount, &payer);
+    events::emit_transfer(owner_id, recipient, token_id, amount, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    use super::*;
+    use crate::{core_impl::get_ft_balance, test_utils::Info, BalanceInfo};
+
+    /// Register accounts 0, 1 and 3 and give account 0 a balance of 1000 of token account 2
+    fn setup() -> (Accounts<Info>, VMContextBuilder) {
+        let mut context = VMContextBuilder::new();
+        context.current_account_id(accounts(0)).account_balance(10_000);
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+        for i in [0, 1, 3].iter() {
+            testing_env!(context
+                .predecessor_account_id(accounts(*i))
+                .attached_deposit(min * 10)
+                .build());
+            near_accounts.storage_deposit(None, None);
+        }
+        let mut account = near_accounts.get_account_checked(&accounts(0).into());
+        account.info.set_balance(&accounts(2).into(), 1000);
+        near_accounts.insert_account_unchecked(&accounts(0).into(), &account);
+        (near_accounts, context)
+    }
+
+    #[test]
+    fn test_transfer_from() {
+        let (mut near_accounts, mut context) = setup();
+        let (owner, spender, recipient, tok): (AccountId, AccountId, AccountId, AccountId) =
+            (accounts(0).into(), accounts(1).into(), accounts(3).into(), accounts(2).into());
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        approve(&mut near_accounts, &spender, &tok, 300);
+        assert_eq!(get_allowance(&near_accounts, &owner, &spender, &tok), 300);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        transfer_from(&mut near_accounts, &owner, &recipient, &tok, 200);
+        assert_eq!(get_allowance(&near_accounts, &owner, &spender, &tok), 100);
+        let balance_of = |near_accounts: &Accounts<Info>, account_id: &AccountId| {
+            get_ft_balance(&near_accounts.get_account_checked(account_id), &tok)
+        };
+        assert_eq!(balance_of(&near_accounts, &owner), 800);
+        assert_eq!(balance_of(&near_accounts, &recipient), 200);
+
+        // Spending the whole allowance removes it
+        transfer_from(&mut near_accounts, &owner, &recipient, &tok, 100);
+        assert_eq!(get_allowance(&near_accounts, &owner, &spender, &tok), 0);
+        assert_eq!(near_accounts.get_account_checked(&owner).info.allowances.len(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_ALLOWANCE")]
+    fn test_transfer_from_above_allowance() {
+        let (mut near_accounts, mut context) = setup();
+        let (owner, spender, recipient, tok): (AccountId, AccountId, AccountId, AccountId) =
+            (accounts(0).into(), accounts(1).into(), accounts(3).into(), accounts(2).into());
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        approve(&mut near_accounts, &spender, &tok, 300);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        transfer_from(&mut near_accounts, &owner, &recipient, &tok, 301);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SELF_APPROVAL")]
+    fn test_approve_self() {
+        let (mut near_accounts, mut context) = setup();
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        approve(&mut near_accounts, &accounts(0).into(), &accounts(2).into(), 300);
+    }
+}
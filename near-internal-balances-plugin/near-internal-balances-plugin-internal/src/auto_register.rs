@@ -0,0 +1,61 @@
+use near_account::{assert_one_yocto, Accounts, AccountsError, AccountsResult, UnwrapOrPanic};
+use near_sdk::AccountId;
+
+use crate::{
+    core_impl::{get_storage_cost_for_one_balance, AccountInfoTrait},
+    state,
+    token_policy::is_token_explicitly_allowed,
+    AutoRegisterPolicy,
+};
+
+const AUTO_REGISTER_POLICY_SUFFIX: &[u8] = b"-auto-register";
+
+pub fn get_auto_register_policy<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+) -> AutoRegisterPolicy {
+    state::read(accounts, AUTO_REGISTER_POLICY_SUFFIX).unwrap_or_panic().unwrap_or_default()
+}
+
+/// Set the auto register policy, the storage is paid from the storage reserve
+pub fn set_auto_register_policy<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    policy: AutoRegisterPolicy,
+) {
+    assert_one_yocto();
+    accounts.assert_owner();
+    state::update(accounts, AUTO_REGISTER_POLICY_SUFFIX, AutoRegisterPolicy::default, |current| {
+        *current = policy
+    })
+    .unwrap_or_panic();
+}
+
+/// Register an account on its first deposit of `token_id` by `sender_id` under the current policy
+///
+/// The account is funded for its own storage and for its first balance. As the storage reserve is the contract's,
+/// it only registers the sender itself, for a deposit of a token in the allowlist
+pub fn try_auto_register<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    account_id: &AccountId,
+    sender_id: &AccountId,
+    token_id: &AccountId,
+) -> AccountsResult<()> {
+    match get_auto_register_policy(accounts) {
+        AutoRegisterPolicy::Disabled => {
+            Err(AccountsError::NotRegistered { account_id: account_id.clone() })
+        }
+        AutoRegisterPolicy::StorageReserve => {
+            if account_id != sender_id {
+                return Err(AccountsError::NotRegistered { account_id: account_id.clone() });
+            }
+            if !is_token_explicitly_allowed(accounts, token_id) {
+                return Err(AccountsError::TokenNotAllowed { token_id: token_id.clone() });
+            }
+            let balance_storage_cost = get_storage_cost_for_one_balance(accounts);
+            accounts.try_register_from_storage_reserve(account_id, balance_storage_cost)
+        }
+        AutoRegisterPolicy::ParkedDeposit => {
+            let balance_storage_cost = get_storage_cost_for_one_balance(accounts);
+            accounts.try_register_from_parked_deposit(account_id, balance_storage_cost)
+        }
+    }
+}
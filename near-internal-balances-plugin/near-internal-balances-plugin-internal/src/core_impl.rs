@@ -1,24 +1,41 @@
-use near_account::{Account, AccountInfoTrait as DefaultAccountInfo, Accounts, NewInfo};
+use std::collections::HashMap;
+
+use near_account::{
+    assert_one_yocto, debug_log, Account, AccountsError, AccountsResult, NewInfo, StoragePayer,
+    UnwrapOrPanic,
+};
+pub use near_account::{AccountInfoTrait as DefaultAccountInfo, Accounts};
 use near_sdk::{
-    assert_one_yocto,
     borsh::{BorshDeserialize, BorshSerialize},
     env,
     json_types::U128,
-    log,
     serde_json::{self, json},
-    AccountId, Balance, Gas,
+    AccountId, Balance, Gas, PromiseResult,
 };
 
-use crate::{BalanceInfo, OnTransferOpts};
+use crate::{
+    auto_register::try_auto_register, events, solvency, storage_payer::get_storage_payer,
+    token_policy::is_token_allowed, BalanceInfo, BalanceTransferRequest, OnTransferOpts,
+    TokenBalance, WithdrawOutcome, WithdrawRequest, MAX_BALANCES_PAGE_SIZE,
+};
 
 pub trait AccountInfoTrait: DefaultAccountInfo + BalanceInfo {}
 
 const RESOLVE_FT_NAME: &str = "resolve_internal_ft_transfer_call";
+const RESOLVE_WITHDRAW_MANY_NAME: &str = "resolve_withdraw_many";
+const RESOLVE_BALANCE_TRANSFER_CALL_NAME: &str = "resolve_balance_transfer_call";
+const ON_INTERNAL_BALANCE_TRANSFER_NAME: &str = "on_internal_balance_transfer";
 const FT_TRANSFER_CALL_METHOD_NAME: &str = "ft_transfer_call";
 const FT_TRANSFER_METHOD_NAME: &str = "ft_transfer";
 
 const GAS_BUFFER: Gas = 5_000_000_000_000;
 const GAS_FOR_INTERNAL_RESOLVE: Gas = 5_000_000_000_000;
+const GAS_FOR_RESOLVE_WITHDRAW_MANY: Gas = 10_000_000_000_000;
+const GAS_FOR_RESOLVE_BALANCE_TRANSFER_CALL: Gas = 10_000_000_000_000;
+/// The least gas left to the receiver of `balance_transfer_call` for `on_internal_balance_transfer`
+const GAS_FOR_ON_INTERNAL_BALANCE_TRANSFER: Gas = 10_000_000_000_000;
+/// Leaves the receiver of an `ft_transfer_call` in `withdraw_many` gas for its own calls
+const GAS_FOR_WITHDRAW_MANY_CALL: Gas = 50_000_000_000_000;
 const GAS_FOR_ON_TRANSFER_NEP141: Gas = 5_000_000_000_000;
 const GAS_FOR_FT_RESOLVE_TRANSFER_NEP141: Gas = 5_000_000_000_000;
 const GAS_FOR_FT_TRANSFER_CALL_NEP141: Gas = GAS_FOR_FT_RESOLVE_TRANSFER_NEP141
@@ -26,23 +43,60 @@ const GAS_FOR_FT_TRANSFER_CALL_NEP141: Gas = GAS_FOR_FT_RESOLVE_TRANSFER_NEP141
     + 25_000_000_000_000
     + GAS_BUFFER;
 
+/// Credit a deposit of the predecessor token
+///
+/// An unregistered account is registered under the auto register policy. The storage of a new balance is paid
+/// for under the storage payer policy, with `sender_id` as the sender. If the deposit cannot be credited,
+/// i.e. the token is not allowed, `msg` is invalid, the account is not registered or cannot pay for the storage,
+/// the full amount is returned as unused so that the token contract refunds it
 pub fn ft_on_transfer<Info: AccountInfoTrait>(
     accounts: &mut Accounts<Info>,
     sender_id: AccountId,
     amount: String,
     msg: String,
 ) -> String {
+    let token_id = env::predecessor_account_id();
+    if !is_token_allowed(accounts, &token_id) {
+        let err = AccountsError::TokenNotAllowed { token_id: token_id.clone() };
+        events::emit_deposit_refund(&sender_id, &token_id, &amount, &err);
+        return amount;
+    }
+
     let opts: OnTransferOpts = if (&msg).len() == 0 {
         OnTransferOpts {
             sender_id: sender_id.clone().into(),
         }
     } else {
-        serde_json::from_str(&msg)
-            .unwrap_or_else(|e| panic!("Failed to deserialize transfer opts: {}", e))
+        match serde_json::from_str(&msg) {
+            Ok(opts) => opts,
+            Err(e) => {
+                let err = AccountsError::InvalidMessage {
+                    reason: format!("Failed to deserialize transfer opts: {}", e),
+                };
+                events::emit_deposit_refund(&sender_id, &token_id, &amount, &err);
+                return amount;
+            }
+        }
     };
-    let token_id = env::predecessor_account_id();
-    let amount = amount.parse::<u128>().unwrap();
-    increase_balance(accounts, &opts.sender_id, &token_id, amount);
+    let amount_parsed = amount
+        .parse::<u128>()
+        .map_err(|e| AccountsError::InvalidMessage {
+            reason: format!("Failed to parse the amount: {}", e),
+        })
+        .unwrap_or_panic();
+    let ret = match accounts.get_account(&opts.sender_id) {
+        Some(_) => Ok(()),
+        None => try_auto_register(accounts, &opts.sender_id, &sender_id, &token_id),
+    }
+    .and_then(|_| {
+        let payer = get_storage_payer(accounts, &sender_id);
+        try_increase_balance_paid_by(accounts, &opts.sender_id, &token_id, amount_parsed, &payer)
+    });
+    if let Err(err) = ret {
+        events::emit_deposit_refund(&opts.sender_id, &token_id, &amount, &err);
+        return amount;
+    }
+    events::emit_deposit(&opts.sender_id, &token_id, amount_parsed);
 
     "0".to_string()
 }
@@ -51,6 +105,23 @@ pub fn get_ft_balance<Info: AccountInfoTrait>(account: &Account<Info>, token_id:
     account.info.get_balance(token_id)
 }
 
+/// Get up to `limit` balances of an account starting at `from_index`, empty if the account is not registered
+pub fn get_ft_balances<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    account_id: &AccountId,
+    from_index: Option<u64>,
+    limit: Option<u64>,
+) -> Vec<TokenBalance> {
+    let limit = limit.unwrap_or(MAX_BALANCES_PAGE_SIZE).min(MAX_BALANCES_PAGE_SIZE);
+    accounts
+        .get_account(account_id)
+        .map(|account| account.info.get_balances(from_index.unwrap_or(0), limit))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(token_id, balance)| TokenBalance { token_id, balance: U128::from(balance) })
+        .collect()
+}
+
 /// Get the cost of adding 1 balance to a user's account
 pub fn get_storage_cost_for_one_balance<Info: AccountInfoTrait>(
     accounts: &mut Accounts<Info>,
@@ -64,13 +135,14 @@ pub fn get_storage_cost_for_one_balance<Info: AccountInfoTrait>(
 
     let storage_usage_init_with_account = env::storage_usage();
 
-    let mut account = accounts.get_account(&account_id).unwrap();
+    let mut account = accounts.get_account_checked(&account_id);
     account.info.set_balance(&token_id, 0);
     accounts.insert_account_unchecked(&account_id, &account);
 
     let storage_usage = env::storage_usage();
 
-    // Remove the inserted account
+    // Remove the inserted balance and account, the nested balance map would otherwise be left behind
+    account.info.remove_balance(&token_id);
     accounts.remove_account_unchecked(&account_id);
 
     return (storage_usage - storage_usage_init_with_account) as u128 * env::storage_byte_cost();
@@ -85,11 +157,238 @@ pub fn balance_transfer<Info: AccountInfoTrait>(
 ) {
     assert_one_yocto();
     let caller = env::predecessor_account_id();
-    if let Some(msg) = msg {
-        log!("Balance transfer message: {}", msg);
+    if let Some(msg) = msg.as_ref() {
+        debug_log!("Balance transfer message: {}", msg);
     }
     subtract_balance(accounts, &caller, token_id, amount);
-    increase_balance(accounts, &recipient, token_id, amount);
+    let payer = get_storage_payer(accounts, &caller);
+    increase_balance_paid_by(accounts, recipient, token_id, amount, &payer);
+    events::emit_transfer(&caller, recipient, token_id, amount, msg.as_ref());
+}
+
+/// Transfer a balance to `recipient` and call `on_internal_balance_transfer` on it with `msg`
+///
+/// Whatever the recipient reports as unused is moved back to the caller by `resolve_balance_transfer_call`
+pub fn balance_transfer_call<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    recipient: AccountId,
+    token_id: AccountId,
+    amount: u128,
+    msg: String,
+) {
+    assert_one_yocto();
+    let caller = env::predecessor_account_id();
+    if recipient == caller {
+        Err(AccountsError::SelfTransfer { account_id: caller.clone() }).unwrap_or_panic()
+    }
+    subtract_balance(accounts, &caller, &token_id, amount);
+    let payer = get_storage_payer(accounts, &caller);
+    increase_balance_paid_by(accounts, &recipient, &token_id, amount, &payer);
+    events::emit_transfer(&caller, &recipient, &token_id, amount, Some(&msg));
+
+    // Leave the recipient all the gas which is not needed to resolve the transfer,
+    // the transfer above is reverted if that is too little
+    let gas = env::prepaid_gas()
+        .saturating_sub(env::used_gas() + GAS_FOR_RESOLVE_BALANCE_TRANSFER_CALL + GAS_BUFFER);
+    if gas < GAS_FOR_ON_INTERNAL_BALANCE_TRANSFER {
+        Err(AccountsError::InsufficientGas {
+            required: GAS_FOR_ON_INTERNAL_BALANCE_TRANSFER,
+            available: gas,
+        })
+        .unwrap_or_panic()
+    }
+    let args = json!({
+        "sender_id": caller,
+        "token_id": token_id,
+        "amount": U128::from(amount),
+        "msg": msg,
+    })
+    .to_string();
+    let on_transfer = env::promise_create(
+        recipient.clone(),
+        ON_INTERNAL_BALANCE_TRANSFER_NAME.as_bytes(),
+        args.as_bytes(),
+        0,
+        gas,
+    );
+    let resolve_args = json!({
+        "sender_id": caller,
+        "recipient": recipient,
+        "token_id": token_id,
+        "amount": U128::from(amount),
+    })
+    .to_string();
+    let resolve = env::promise_then(
+        on_transfer,
+        env::current_account_id(),
+        RESOLVE_BALANCE_TRANSFER_CALL_NAME.as_bytes(),
+        resolve_args.as_bytes(),
+        0,
+        GAS_FOR_RESOLVE_BALANCE_TRANSFER_CALL,
+    );
+    env::promise_return(resolve);
+}
+
+/// Move the amount which the recipient of `balance_transfer_call` did not use back to the sender
+///
+/// The whole amount is unused if the call failed. The refund is limited to what is left of the recipient's
+/// balance and stays with the recipient if the sender unregistered in the meantime
+pub fn resolve_balance_transfer_call<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    sender_id: &AccountId,
+    recipient: &AccountId,
+    token_id: &AccountId,
+    amount: U128,
+) -> U128 {
+    let amount: u128 = amount.into();
+    let amount_unused = match env::promise_result(0) {
+        PromiseResult::Successful(data) => serde_json::from_slice::<U128>(&data)
+            .map(|unused| unused.0.min(amount))
+            .unwrap_or(amount),
+        _ => amount,
+    };
+    if amount_unused == 0 || accounts.get_account(sender_id).is_none() {
+        return U128(amount);
+    }
+
+    let recipient_balance = accounts
+        .get_account(recipient)
+        .map(|account| get_ft_balance(&account, token_id))
+        .unwrap_or(0);
+    let refund = amount_unused.min(recipient_balance);
+    if refund > 0 {
+        subtract_balance(accounts, recipient, token_id, refund);
+        increase_balance(accounts, sender_id, token_id, refund);
+        events::emit_transfer(recipient, sender_id, token_id, refund, None);
+    }
+    U128(amount - refund)
+}
+
+/// Transfer balances from the caller to several recipients, either all transfers succeed or none do
+///
+/// Everything which can fail is checked before anything is changed: the recipients, the caller's total of each
+/// token and the storage of the whole batch, which is paid for under the storage payer policy.
+/// The caller's balances are then subtracted within a single storage check, as are the balances credited
+/// to each recipient
+pub fn balance_transfer_batch<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    transfers: Vec<BalanceTransferRequest>,
+    memo: Option<String>,
+) {
+    assert_one_yocto();
+    let caller = env::predecessor_account_id();
+    let transfers: Vec<(AccountId, AccountId, Balance)> = transfers
+        .into_iter()
+        .map(|transfer| (transfer.recipient.into(), transfer.token_id.into(), transfer.amount.0))
+        .collect();
+    validate_transfer_batch(accounts, &caller, &transfers).unwrap_or_panic();
+    let credits = group_transfer_batch(&transfers);
+    let payer = get_storage_payer(accounts, &caller);
+    let storage_costs = get_transfer_batch_storage_costs(accounts, &credits);
+    accounts.check_storage_costs_payable(&storage_costs, &payer).unwrap_or_panic();
+
+    let mut caller_account = accounts.get_account_checked(&caller);
+    accounts.check_storage(&mut caller_account, &caller, |accounts, account| {
+        for (_, token_id, amount) in transfers.iter() {
+            let balance = account.info.get_balance(token_id);
+            account.info.set_balance(token_id, balance - amount);
+            solvency::decrease_total(accounts, token_id, *amount);
+        }
+    });
+    for (recipient, amounts) in credits.iter() {
+        let mut account = accounts.get_account_checked(recipient);
+        accounts.check_storage_paid_by(&mut account, recipient, &payer, |accounts, account| {
+            for (token_id, amount) in amounts.iter() {
+                let balance = account.info.get_balance(token_id);
+                account.info.set_balance(token_id, balance + amount);
+                solvency::increase_total(accounts, token_id, *amount);
+            }
+        });
+    }
+    for (recipient, token_id, amount) in transfers.iter() {
+        events::emit_transfer(&caller, recipient, token_id, *amount, memo.as_ref());
+    }
+}
+
+/// Group the amounts credited by a batch per recipient and token, in the order the recipients first appear
+fn group_transfer_batch(
+    transfers: &[(AccountId, AccountId, Balance)],
+) -> Vec<(AccountId, Vec<(AccountId, Balance)>)> {
+    let mut credits: Vec<(AccountId, Vec<(AccountId, Balance)>)> = vec![];
+    for (recipient, token_id, amount) in transfers.iter() {
+        let index = match credits.iter().position(|(id, _)| id == recipient) {
+            Some(index) => index,
+            None => {
+                credits.push((recipient.clone(), vec![]));
+                credits.len() - 1
+            }
+        };
+        let amounts = &mut credits[index].1;
+        match amounts.iter_mut().find(|(id, _)| id == token_id) {
+            Some((_, total)) => *total += amount,
+            None => amounts.push((token_id.clone(), *amount)),
+        }
+    }
+    credits
+}
+
+/// Get the storage cost of the balances a batch adds to each recipient
+///
+/// Each missing balance is added and then removed again to measure its storage
+fn get_transfer_batch_storage_costs<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    credits: &[(AccountId, Vec<(AccountId, Balance)>)],
+) -> Vec<(AccountId, Balance)> {
+    credits
+        .iter()
+        .map(|(recipient, amounts)| {
+            let mut account = accounts.get_account_checked(recipient);
+            let storage_start = env::storage_usage();
+            let mut new_balances = vec![];
+            for (token_id, _) in amounts.iter() {
+                let storage_prior = env::storage_usage();
+                account.info.set_balance(token_id, account.info.get_balance(token_id));
+                if env::storage_usage() > storage_prior {
+                    new_balances.push(token_id);
+                }
+            }
+            let storage_usage = env::storage_usage() - storage_start;
+            for token_id in new_balances {
+                account.info.remove_balance(token_id);
+            }
+            (recipient.clone(), storage_usage as u128 * env::storage_byte_cost())
+        })
+        .collect()
+}
+
+/// Check that every recipient is registered and is not the caller,
+/// and that the caller holds the total of each token in the batch
+fn validate_transfer_batch<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    caller: &AccountId,
+    transfers: &[(AccountId, AccountId, Balance)],
+) -> AccountsResult<()> {
+    let caller_account = accounts.try_get_account(caller)?;
+    let mut totals: HashMap<&AccountId, Balance> = HashMap::new();
+    for (recipient, token_id, amount) in transfers.iter() {
+        if recipient == caller {
+            return Err(AccountsError::SelfTransfer { account_id: caller.clone() });
+        }
+        accounts.try_get_account(recipient)?;
+        *totals.entry(token_id).or_insert(0) += amount;
+    }
+    for (token_id, total) in totals {
+        let balance = caller_account.info.get_balance(token_id);
+        if balance < total {
+            return Err(AccountsError::InsufficientBalance {
+                account_id: caller.clone(),
+                token_id: token_id.clone(),
+                balance,
+                amount: total,
+            });
+        }
+    }
+    Ok(())
 }
 
 pub fn withdraw_to<Info: AccountInfoTrait>(
@@ -102,19 +401,110 @@ pub fn withdraw_to<Info: AccountInfoTrait>(
     assert_one_yocto();
     let caller = env::predecessor_account_id();
 
-    // TODO: in sep function
-    assert_eq!(
-        env::attached_deposit(),
-        1,
-        "Expected an attached deposit of 1"
-    );
-
     let recipient = recipient.unwrap_or(caller.clone());
 
-    let prom = internal_ft_transfer(accounts, &caller, &token_id, recipient, amount, msg, None);
+    let prom = internal_ft_transfer(accounts, &caller, &token_id, recipient, amount, msg);
     env::promise_return(prom);
 }
 
+/// Withdraw to `recipient` with `ft_transfer_call`, so that the recipient can act on the tokens with `msg`
+///
+/// Whatever the recipient does not use is refunded to the caller's internal balance
+pub fn withdraw_to_call<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    token_id: AccountId,
+    amount: u128,
+    recipient: AccountId,
+    msg: String,
+) {
+    assert_one_yocto();
+    let caller = env::predecessor_account_id();
+
+    let prom =
+        internal_ft_transfer_call(accounts, &caller, &token_id, recipient, amount, Some(msg), None);
+    env::promise_return(prom);
+}
+
+/// The maximum number of withdrawals in `withdraw_many`, so that all of them fit in the gas limit
+pub const MAX_WITHDRAWALS: usize = 4;
+
+/// Withdraw several tokens at once
+///
+/// The withdrawals run in parallel and each one is resolved on its own, so a failing token is refunded
+/// to the caller's internal balance without affecting the others. The outcomes are returned by
+/// `resolve_withdraw_many`
+pub fn withdraw_many<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    withdrawals: Vec<WithdrawRequest>,
+) {
+    assert_one_yocto();
+    if withdrawals.is_empty() {
+        Err(AccountsError::InvalidMessage { reason: "Expected at least 1 withdrawal".to_string() })
+            .unwrap_or_panic()
+    }
+    if withdrawals.len() > MAX_WITHDRAWALS {
+        Err(AccountsError::TooManyWithdrawals { count: withdrawals.len(), max: MAX_WITHDRAWALS })
+            .unwrap_or_panic()
+    }
+    let caller = env::predecessor_account_id();
+
+    let mut token_ids = vec![];
+    let mut amounts = vec![];
+    let legs: Vec<u64> = withdrawals
+        .into_iter()
+        .map(|withdrawal| {
+            let token_id: AccountId = withdrawal.token_id.into();
+            let recipient = withdrawal.recipient.map(|r| r.into()).unwrap_or(caller.clone());
+            let amount = withdrawal.amount.0;
+            token_ids.push(token_id.clone());
+            amounts.push(withdrawal.amount);
+            match withdrawal.msg {
+                Some(msg) => internal_ft_transfer_call(
+                    accounts,
+                    &caller,
+                    &token_id,
+                    recipient,
+                    amount,
+                    Some(msg),
+                    Some(GAS_FOR_WITHDRAW_MANY_CALL),
+                ),
+                None => internal_ft_transfer(accounts, &caller, &token_id, recipient, amount, None),
+            }
+        })
+        .collect();
+
+    let resolve_args = json!({ "token_ids": token_ids, "amounts": amounts }).to_string();
+    let prom = env::promise_then(
+        env::promise_and(&legs),
+        env::current_account_id(),
+        RESOLVE_WITHDRAW_MANY_NAME.as_bytes(),
+        resolve_args.as_bytes(),
+        0,
+        GAS_FOR_RESOLVE_WITHDRAW_MANY,
+    );
+    env::promise_return(prom);
+}
+
+/// Collect the outcome of each withdrawal of `withdraw_many` from the results of their resolves
+pub fn resolve_withdraw_many(
+    token_ids: Vec<AccountId>,
+    amounts: Vec<U128>,
+) -> Vec<WithdrawOutcome> {
+    token_ids
+        .into_iter()
+        .zip(amounts)
+        .enumerate()
+        .map(|(index, (token_id, amount))| {
+            let amount_used = match env::promise_result(index as u64) {
+                PromiseResult::Successful(data) => serde_json::from_slice::<U128>(&data).ok(),
+                _ => None,
+            };
+            let resolved = amount_used.is_some();
+            WithdrawOutcome { token_id, amount, amount_used: amount_used.unwrap_or(U128(0)), resolved }
+        })
+        .collect()
+}
+
 fn internal_ft_transfer<Info: AccountInfoTrait>(
     accounts: &mut Accounts<Info>,
     sender: &AccountId,
@@ -122,36 +512,23 @@ fn internal_ft_transfer<Info: AccountInfoTrait>(
     recipient: AccountId,
     amount: u128,
     msg: Option<String>,
-    prior_promise: Option<u64>,
 ) -> u64 {
-    let data = get_transfer_data(recipient, U128::from(amount), sender.clone(), msg);
-
     // TODO: update
     subtract_balance(accounts, sender, token_id, amount);
+    events::emit_withdraw(sender, &recipient, token_id, amount);
 
-    let ft_transfer_prom = match prior_promise {
-        None => {
-            let prom = env::promise_batch_create(token_id);
-            env::promise_batch_action_function_call(
-                prom,
-                FT_TRANSFER_METHOD_NAME.as_bytes(),
-                &data,
-                1,
-                GAS_FOR_FT_TRANSFER_CALL_NEP141,
-            );
-            prom
-        }
-        Some(prior_prom) => env::promise_then(
-            prior_prom,
-            token_id.to_string(),
-            FT_TRANSFER_METHOD_NAME.as_bytes(),
-            &data,
-            1,
-            GAS_FOR_FT_TRANSFER_CALL_NEP141,
-        ),
-    };
+    let data = get_transfer_data(recipient, U128::from(amount), sender.clone(), msg);
+
+    let ft_transfer_prom = env::promise_batch_create(token_id);
+    env::promise_batch_action_function_call(
+        ft_transfer_prom,
+        FT_TRANSFER_METHOD_NAME.as_bytes(),
+        &data,
+        1,
+        GAS_FOR_FT_TRANSFER_CALL_NEP141,
+    );
     let internal_resolve_args =
-        get_internal_resolve_data(&sender, token_id, U128::from(amount), false).unwrap();
+        get_internal_resolve_data(sender, token_id, U128::from(amount), false).unwrap();
     env::promise_then(
         ft_transfer_prom,
         env::current_account_id(),
@@ -162,69 +539,41 @@ fn internal_ft_transfer<Info: AccountInfoTrait>(
     )
 }
 
-// TODO: integrate
-fn internal_ft_transfer_call<Info: AccountInfoTrait>(
-    accounts: &mut Accounts<Info>,
-    token_id: &AccountId,
-    recipient: AccountId,
-    amount: U128,
-    sender: AccountId,
-    prior_promise: Option<u64>,
-) -> u64 {
-    _internal_ft_transfer_call(
-        accounts,
-        token_id,
-        recipient,
-        amount,
-        sender,
-        prior_promise,
-        None,
-        1,
-    )
-}
-
-/// Do an internal transfer and subtract the internal balance for {@param sender}
+/// Subtract the internal balance of `sender` and `ft_transfer_call` it to `recipient` with `gas`
 ///
-/// If there is a custom message, use that for the ft transfer. If not, use the default On Transfer Message
-fn _internal_ft_transfer_call<Info: AccountInfoTrait>(
+/// If there is a custom message, use that for the ft transfer. If not, use the default On Transfer Message.
+/// Without `gas` the recipient gets all the gas which is not needed to resolve the transfer, which is only
+/// worked out once the balance is subtracted. The amount used by the recipient is resolved by
+/// `resolve_internal_ft_transfer_call`
+fn internal_ft_transfer_call<Info: AccountInfoTrait>(
     accounts: &mut Accounts<Info>,
+    sender: &AccountId,
     token_id: &AccountId,
     recipient: AccountId,
-    amount: U128,
-    sender: AccountId,
-    prior_promise: Option<u64>,
+    amount: u128,
     custom_message: Option<String>,
-    amount_near: Balance,
+    gas: Option<Gas>,
 ) -> u64 {
-    let data = get_transfer_call_data(recipient, amount.clone(), sender.clone(), custom_message);
-
-    let amount_parsed = amount.0;
+    subtract_balance(accounts, sender, token_id, amount);
+    events::emit_withdraw(sender, &recipient, token_id, amount);
+    let gas = gas.unwrap_or_else(|| {
+        env::prepaid_gas()
+            .saturating_sub(env::used_gas() + GAS_FOR_INTERNAL_RESOLVE + GAS_BUFFER)
+            .max(GAS_FOR_FT_TRANSFER_CALL_NEP141)
+    });
 
-    subtract_balance(accounts, &sender, token_id, amount_parsed);
+    let data = get_transfer_call_data(recipient, U128::from(amount), sender.clone(), custom_message);
 
-    let ft_transfer_prom = match prior_promise {
-        None => {
-            let prom_batch = env::promise_batch_create(token_id);
-            env::promise_batch_action_function_call(
-                prom_batch,
-                FT_TRANSFER_CALL_METHOD_NAME.as_bytes(),
-                &data,
-                amount_near,
-                GAS_FOR_FT_TRANSFER_CALL_NEP141,
-            );
-            prom_batch
-        }
-        Some(prior_prom) => env::promise_then(
-            prior_prom,
-            token_id.to_string(),
-            FT_TRANSFER_CALL_METHOD_NAME.as_bytes(),
-            &data,
-            amount_near,
-            GAS_FOR_FT_TRANSFER_CALL_NEP141,
-        ),
-    };
+    let ft_transfer_prom = env::promise_batch_create(token_id);
+    env::promise_batch_action_function_call(
+        ft_transfer_prom,
+        FT_TRANSFER_CALL_METHOD_NAME.as_bytes(),
+        &data,
+        1,
+        gas,
+    );
     let internal_resolve_args =
-        get_internal_resolve_data(&sender, &token_id, amount, true).unwrap();
+        get_internal_resolve_data(sender, token_id, U128::from(amount), true).unwrap();
     env::promise_then(
         ft_transfer_prom,
         env::current_account_id(),
@@ -253,27 +602,33 @@ pub fn resolve_internal_ft_transfer_call<Info: AccountInfoTrait>(
     // let account = accounts.get_account_checked(account_id);
     match near_sdk::utils::promise_result_as_success() {
         None => {
-            log!("The FT transfer call failed, redepositing funds");
+            debug_log!("The FT transfer call failed, redepositing funds");
             increase_balance(accounts, account_id, &token_id, amount);
+            events::emit_refund(account_id, &token_id, amount);
             U128(0)
         }
         Some(data) => {
             let amount_used = if is_ft_call {
                 let amount_used_str: String = serde_json::from_slice(data.as_slice())
-                    .unwrap_or_else(|e| {
-                        panic!("Failed to deserialize ft_transfer_call result {}", e)
-                    });
+                    .map_err(|e| AccountsError::InvalidPromiseResult {
+                        reason: format!("Failed to deserialize ft_transfer_call result: {}", e),
+                    })
+                    .unwrap_or_panic();
                 amount_used_str
                     .parse::<u128>()
-                    .unwrap_or_else(|e| panic!("Failed to parse result with {}", e))
+                    .map_err(|e| AccountsError::InvalidPromiseResult {
+                        reason: format!("Failed to parse ft_transfer_call result: {}", e),
+                    })
+                    .unwrap_or_panic()
             } else {
                 amount
             };
-            // TODO: err handling?
-            let amount_unused = amount - amount_used;
-            log!("Amount unused {}", amount_unused);
+            // The token contract never reports more than the amount as used
+            let amount_unused = amount.saturating_sub(amount_used);
+            debug_log!("Amount unused {}", amount_unused);
             if amount_unused > 0 {
                 increase_balance(accounts, account_id, &token_id, amount_unused);
+                events::emit_refund(account_id, &token_id, amount_unused);
             }
             U128(amount_used)
         }
@@ -285,11 +640,65 @@ pub fn increase_balance<Info: AccountInfoTrait>(
     account_id: &AccountId,
     token_id: &AccountId,
     amount: u128,
+) {
+    increase_balance_paid_by(accounts, account_id, token_id, amount, &StoragePayer::Account)
+}
+
+/// Same as `increase_balance` but returns an error instead of panicking
+///
+/// The account pays for the storage, if it cannot the balance is rolled back so that nothing is changed
+pub fn try_increase_balance<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    account_id: &AccountId,
+    token_id: &AccountId,
+    amount: u128,
+) -> AccountsResult<()> {
+    try_increase_balance_paid_by(accounts, account_id, token_id, amount, &StoragePayer::Account)
+}
+
+/// Same as `try_increase_balance` but the storage for a new balance is paid for by `payer`
+pub fn try_increase_balance_paid_by<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    account_id: &AccountId,
+    token_id: &AccountId,
+    amount: u128,
+    payer: &StoragePayer,
+) -> AccountsResult<()> {
+    let mut account = accounts.try_get_account(account_id)?;
+    let current_balance = get_ft_balance(&account, token_id);
+    let balances_len = account.info.balances_len();
+
+    let updated = current_balance + amount;
+    let ret =
+        accounts.try_check_storage_paid_by(&mut account, account_id, payer, |accounts, account| {
+            account.info.set_balance(token_id, updated);
+            solvency::increase_total(accounts, token_id, amount);
+        });
+    if let Err(err) = ret {
+        if account.info.balances_len() > balances_len {
+            account.info.remove_balance(token_id);
+        } else {
+            account.info.set_balance(token_id, current_balance);
+        }
+        solvency::revert_increase_total(accounts, token_id, amount);
+        accounts.insert_account_unchecked(account_id, &account);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Same as `increase_balance` but the storage for a new balance is paid for by `payer`
+pub fn increase_balance_paid_by<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    account_id: &AccountId,
+    token_id: &AccountId,
+    amount: u128,
+    payer: &StoragePayer,
 ) {
     let mut account = accounts.get_account_checked(account_id);
     let current_balance = get_ft_balance(&account, token_id);
 
-    log!(
+    debug_log!(
         "Adding {} from {} for token {} with current balance {}",
         amount,
         account_id,
@@ -298,8 +707,10 @@ pub fn increase_balance<Info: AccountInfoTrait>(
     );
 
     let updated = current_balance + amount;
-    account.info.set_balance(token_id, updated);
-    accounts.insert_account_check_storage(account_id, &mut account);
+    accounts.check_storage_paid_by(&mut account, account_id, payer, |accounts, account| {
+        account.info.set_balance(token_id, updated);
+        solvency::increase_total(accounts, token_id, amount);
+    });
 }
 
 pub fn subtract_balance<Info: AccountInfoTrait>(
@@ -308,14 +719,29 @@ pub fn subtract_balance<Info: AccountInfoTrait>(
     token_id: &AccountId,
     amount: u128,
 ) {
-    let mut account = accounts.get_account_checked(account_id);
+    try_subtract_balance(accounts, account_id, token_id, amount).unwrap_or_panic()
+}
+
+/// Same as `subtract_balance` but returns an error if the account is not registered or the balance is too low
+pub fn try_subtract_balance<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    account_id: &AccountId,
+    token_id: &AccountId,
+    amount: u128,
+) -> AccountsResult<()> {
+    let mut account = accounts.try_get_account(account_id)?;
     let current_balance = get_ft_balance(&account, token_id);
 
     if current_balance < amount {
-        panic!("The callee did not deposit sufficient funds. Current balance: {}, requested amount {}, token {}", current_balance, amount, token_id);
+        return Err(AccountsError::InsufficientBalance {
+            account_id: account_id.clone(),
+            token_id: token_id.clone(),
+            balance: current_balance,
+            amount,
+        });
     }
 
-    log!(
+    debug_log!(
         "Subtracting {} from {} for token {} with current balance {}",
         amount,
         account_id,
@@ -324,8 +750,11 @@ pub fn subtract_balance<Info: AccountInfoTrait>(
     );
 
     let updated = current_balance - amount;
-    account.info.set_balance(token_id, updated);
-    accounts.insert_account_check_storage(account_id, &mut account);
+    accounts.try_check_storage(&mut account, account_id, |accounts, account| {
+        account.info.set_balance(token_id, updated);
+        solvency::decrease_total(accounts, token_id, amount);
+    })?;
+    Ok(())
 }
 
 /********** Helper functions **************/
@@ -377,78 +806,13 @@ fn get_transfer_call_data(
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
-    const INIT_ACCOUNT_BAL: u128 = 10_000;
-
-    use std::convert::TryFrom;
-
     use super::*;
-    use near_account::NewInfo;
+    use crate::test_utils::{get_context, get_near_accounts, Info};
     use near_contract_standards::storage_management::StorageManagement;
-    use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-    use near_sdk::collections::UnorderedMap;
-    use near_sdk::json_types::ValidAccountId;
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::test_utils::{accounts, testing_env_with_promise_results};
     use near_sdk::testing_env;
     use near_sdk::MockedBlockchain;
 
-    #[derive(BorshSerialize, BorshDeserialize)]
-    struct Info {
-        pub internal_balance: UnorderedMap<AccountId, Balance>,
-    }
-    impl NewInfo for Info {
-        fn default_from_account_id(account_id: AccountId) -> Self {
-            Self {
-                internal_balance: UnorderedMap::new((format!("{}-bals-i", &account_id)).as_bytes()),
-            }
-        }
-    }
-
-    impl BalanceInfo for Info {
-        fn get_balance(&self, token_id: &AccountId) -> Balance {
-            // TODO: allow for custom balance field
-            self.internal_balance.get(token_id).unwrap_or(0)
-        }
-
-        fn set_balance(&mut self, token_id: &AccountId, balance: Balance) {
-            self.internal_balance.insert(token_id, &balance);
-        }
-    }
-
-    fn get_near_accounts(
-        mut context: VMContextBuilder,
-    ) -> (
-        AccountId,
-        AccountId,
-        Accounts<Info>,
-        Account<Info>,
-        VMContextBuilder,
-    ) {
-        let mut near_accounts = Accounts::<Info>::new();
-        let account: AccountId = accounts(0).into();
-        let tok: AccountId = accounts(2).into();
-        let min = near_accounts.storage_balance_bounds().min.0;
-        testing_env!(context.attached_deposit(min * 10).build());
-        near_accounts.storage_deposit(
-            Some(ValidAccountId::try_from(account.clone()).unwrap()),
-            None,
-        );
-        testing_env!(context.attached_deposit(1).build());
-        let near_account = near_accounts.get_account_checked(&account);
-
-        (account, tok, near_accounts, near_account, context)
-    }
-
-    // mock the context for testing, notice "signer_account_id" that was accessed above from env::
-    fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
-        let mut builder = VMContextBuilder::new();
-        builder
-            .current_account_id(accounts(0))
-            .signer_account_id(predecessor_account_id.clone())
-            .predecessor_account_id(predecessor_account_id)
-            .account_balance(INIT_ACCOUNT_BAL);
-        builder
-    }
-
     // TODO: register token's with deposits...
     // TODO: should panic type
 
@@ -528,4 +892,656 @@ mod tests {
         let bal = get_ft_balance(&near_account, &tok);
         assert_eq!(bal, 1_100);
     }
+
+    #[test]
+    fn test_balance_transfer_sender_pays_storage() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+
+        let min = near_accounts.storage_balance_bounds().min.0;
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::storage_payer::set_storage_payer_policy(
+            &mut near_accounts,
+            crate::StoragePayerPolicy::Sender,
+        );
+        let sender_near_prior = near_accounts.get_account_checked(&account).near_amount;
+        balance_transfer(&mut near_accounts, &accounts(1).into(), &tok, 100, None);
+
+        let sender = near_accounts.get_account_checked(&account);
+        let recipient = near_accounts.get_account_checked(&accounts(1).into());
+        assert_eq!(get_ft_balance(&recipient, &tok), 100);
+        assert_eq!(recipient.sponsor_id, Some(account.clone()));
+        assert!(recipient.sponsored_amount > 0);
+        assert_eq!(sender.near_amount, sender_near_prior - recipient.sponsored_amount);
+    }
+
+    #[test]
+    fn test_deposit_replaces_sender_paying_storage() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+
+        let min = near_accounts.storage_balance_bounds().min.0;
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::storage_payer::set_storage_payer_policy(
+            &mut near_accounts,
+            crate::StoragePayerPolicy::Sender,
+        );
+        let sender_near_prior = near_accounts.get_account_checked(&account).near_amount;
+        balance_transfer(&mut near_accounts, &accounts(1).into(), &tok, 100, None);
+
+        // A treasury sponsoring the recipient takes the place of the sender, which gets its Near back
+        testing_env!(context.predecessor_account_id(accounts(3)).attached_deposit(min).build());
+        near_accounts.storage_deposit(Some(accounts(1)), None);
+        let recipient = near_accounts.get_account_checked(&accounts(1).into());
+        assert_eq!(recipient.sponsor_id, Some(accounts(3).into()));
+        assert!(!recipient.sponsor_is_storage_payer);
+        assert_eq!(near_accounts.get_account_checked(&account).near_amount, sender_near_prior);
+    }
+
+    #[test]
+    fn test_balance_transfer_contract_pays_storage() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+
+        let min = near_accounts.storage_balance_bounds().min.0;
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::storage_payer::set_storage_payer_policy(
+            &mut near_accounts,
+            crate::StoragePayerPolicy::Contract,
+        );
+        let reserve_prior = near_accounts.storage_reserve;
+        balance_transfer(&mut near_accounts, &accounts(1).into(), &tok, 100, None);
+
+        // The storage is lent from the reserve rather than created out of nothing
+        let recipient = near_accounts.get_account_checked(&accounts(1).into());
+        assert_eq!(get_ft_balance(&recipient, &tok), 100);
+        assert_eq!(recipient.sponsor_id, None);
+        assert!(recipient.storage_debt > 0);
+        assert_eq!(near_accounts.storage_reserve, reserve_prior - recipient.storage_debt);
+    }
+
+    #[test]
+    fn test_balance_transfer_recipient_with_other_sponsor_pays_storage() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+
+        // The recipient is sponsored by another account, with enough Near for a balance
+        let min = near_accounts.storage_balance_bounds().min.0;
+        testing_env!(context.predecessor_account_id(accounts(3)).attached_deposit(min * 2).build());
+        near_accounts.storage_deposit(Some(accounts(1)), None);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::storage_payer::set_storage_payer_policy(
+            &mut near_accounts,
+            crate::StoragePayerPolicy::Sender,
+        );
+        let sender_near_prior = near_accounts.get_account_checked(&account).near_amount;
+        balance_transfer(&mut near_accounts, &accounts(1).into(), &tok, 100, None);
+
+        // An account has a single sponsor, so the recipient pays instead of the sender
+        let recipient = near_accounts.get_account_checked(&accounts(1).into());
+        assert_eq!(get_ft_balance(&recipient, &tok), 100);
+        assert_eq!(recipient.sponsor_id, Some(accounts(3).into()));
+        assert_eq!(recipient.sponsored_amount, min * 2);
+        assert_eq!(near_accounts.get_account_checked(&account).near_amount, sender_near_prior);
+    }
+
+    #[test]
+    fn test_on_transfer_sender_pays_storage() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        let min = near_accounts.storage_balance_bounds().min.0;
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::storage_payer::set_storage_payer_policy(
+            &mut near_accounts,
+            crate::StoragePayerPolicy::Sender,
+        );
+
+        // The sender deposits for an account which cannot pay for a new balance itself
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        let sender_near_prior = near_accounts.get_account_checked(&account).near_amount;
+        let msg = serde_json::to_string(&OnTransferOpts { sender_id: accounts(1).into() }).unwrap();
+        let amount_unused =
+            ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), msg);
+        assert_eq!(amount_unused, "0");
+
+        let recipient = near_accounts.get_account_checked(&accounts(1).into());
+        assert_eq!(get_ft_balance(&recipient, &tok), 1000);
+        assert_eq!(recipient.sponsor_id, Some(account.clone()));
+        assert!(recipient.sponsor_is_storage_payer);
+        let sender = near_accounts.get_account_checked(&account);
+        assert_eq!(sender.near_amount, sender_near_prior - recipient.sponsored_amount);
+    }
+
+    #[test]
+    fn test_get_ft_balances() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        ft_on_transfer(&mut near_accounts, account.clone(), 50.to_string(), "".to_string());
+
+        let balances = get_ft_balances(&near_accounts, &account, None, None);
+        assert_eq!(
+            balances,
+            vec![
+                TokenBalance { token_id: tok, balance: U128(1000) },
+                TokenBalance { token_id: accounts(3).into(), balance: U128(50) },
+            ]
+        );
+        assert_eq!(get_ft_balances(&near_accounts, &account, Some(1), Some(1)).len(), 1);
+        assert!(get_ft_balances(&near_accounts, &accounts(1).into(), None, None).is_empty());
+    }
+
+    #[test]
+    fn test_total_internal_balance() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, _context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        assert_eq!(solvency::get_total_internal_balance(&near_accounts, &tok), 1000);
+
+        subtract_balance(&mut near_accounts, &account, &tok, 400);
+        assert_eq!(solvency::get_total_internal_balance(&near_accounts, &tok), 600);
+        assert_eq!(solvency::get_total_internal_balance(&near_accounts, &accounts(3).into()), 0);
+
+        // Another instance of the plugin keeps its own totals
+        let other_accounts = Accounts::<Info>::new_with_prefix(b"other-accounts".to_vec());
+        assert_eq!(solvency::get_total_internal_balance(&other_accounts, &tok), 0);
+    }
+
+    #[test]
+    fn test_first_depositor_pays_for_the_total() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, _tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        let min = near_accounts.storage_balance_bounds().min.0;
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(min * 10)
+            .build());
+        near_accounts.storage_deposit(None, None);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        let storage_usage = |near_accounts: &Accounts<Info>, account_id: &AccountId| {
+            near_accounts.get_account_checked(account_id).storage_usage
+        };
+        let first_prior = storage_usage(&near_accounts, &account);
+        let storage_prior = env::storage_usage();
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        let first_increase = storage_usage(&near_accounts, &account) - first_prior;
+        assert_eq!(first_increase, env::storage_usage() - storage_prior);
+
+        let second_prior = storage_usage(&near_accounts, &accounts(1).into());
+        ft_on_transfer(&mut near_accounts, accounts(1).into(), 1000.to_string(), "".to_string());
+        let second_increase = storage_usage(&near_accounts, &accounts(1).into()) - second_prior;
+        assert!(second_increase > 0);
+        assert!(first_increase > second_increase);
+    }
+
+    #[test]
+    fn test_verify_solvency_reports_surplus() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, context) = get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+
+        let contract_balance = serde_json::to_vec(&U128(1500)).unwrap();
+        testing_env_with_promise_results(
+            context.build(),
+            PromiseResult::Successful(contract_balance),
+        );
+        let report = solvency::resolve_verify_solvency(&near_accounts, tok.clone());
+        assert_eq!(report.shortfall, U128(0));
+        assert_eq!(report.surplus, U128(500));
+        assert!(report.is_solvent);
+
+        let contract_balance = serde_json::to_vec(&U128(600)).unwrap();
+        testing_env_with_promise_results(
+            context.build(),
+            PromiseResult::Successful(contract_balance),
+        );
+        let report = solvency::resolve_verify_solvency(&near_accounts, tok);
+        assert_eq!(report.shortfall, U128(400));
+        assert_eq!(report.surplus, U128(0));
+        assert!(!report.is_solvent);
+    }
+
+    #[test]
+    fn test_on_transfer_refunds_disallowed_tokens() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        // The token lists are paid for by the storage reserve
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::token_policy::set_token_policy(&mut near_accounts, crate::TokenPolicy::Allowlist);
+        crate::token_policy::add_allowed_tokens(&mut near_accounts, vec![accounts(3).into()]);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        let amount_unused =
+            ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        assert_eq!(amount_unused, "1000");
+        assert_eq!(get_ft_balance(&near_accounts.get_account_checked(&account), &tok), 0);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let amount_unused =
+            ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        assert_eq!(amount_unused, "0");
+
+        // Switching to the denylist does not deny the tokens of the allowlist
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        crate::token_policy::set_token_policy(&mut near_accounts, crate::TokenPolicy::Denylist);
+        crate::token_policy::add_denied_tokens(&mut near_accounts, vec![tok.clone()]);
+        assert_eq!(
+            crate::token_policy::get_allowed_tokens(&near_accounts),
+            vec![accounts(3).to_string()]
+        );
+        assert!(near_accounts.storage_reserve < min);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).attached_deposit(0).build());
+        let amount_unused =
+            ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        assert_eq!(amount_unused, "0");
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let amount_unused =
+            ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        assert_eq!(amount_unused, "1000");
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_NEAR")]
+    fn test_token_list_without_storage_reserve() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.attached_deposit(1).build());
+        let mut near_accounts = Accounts::<Info>::new();
+        crate::token_policy::add_allowed_tokens(&mut near_accounts, vec![accounts(2).into()]);
+    }
+
+    #[test]
+    fn test_token_policy_per_instance() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let other_accounts = Accounts::<Info>::new_with_prefix(b"other-accounts".to_vec());
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::token_policy::set_token_policy(&mut near_accounts, crate::TokenPolicy::Allowlist);
+        crate::token_policy::add_allowed_tokens(&mut near_accounts, vec![accounts(2).into()]);
+
+        let tok: AccountId = accounts(3).into();
+        assert!(!crate::token_policy::is_token_allowed(&near_accounts, &tok));
+        assert!(crate::token_policy::is_token_allowed(&other_accounts, &tok));
+        assert!(crate::token_policy::get_allowed_tokens(&other_accounts).is_empty());
+    }
+
+    #[test]
+    fn test_on_transfer_refunds_undeliverable_deposits() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, _context) =
+            get_near_accounts(context);
+
+        // The target account is not registered
+        let msg = serde_json::to_string(&OnTransferOpts { sender_id: accounts(1).into() }).unwrap();
+        let amount_unused =
+            ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), msg);
+        assert_eq!(amount_unused, "1000");
+
+        // The message is not valid JSON
+        let amount_unused =
+            ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "{".to_string());
+        assert_eq!(amount_unused, "1000");
+        assert_eq!(get_ft_balance(&near_accounts.get_account_checked(&account), &tok), 0);
+        assert_eq!(solvency::get_total_internal_balance(&near_accounts, &tok), 0);
+    }
+
+    #[test]
+    fn test_on_transfer_refunds_on_storage_shortfall() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+        testing_env!(context.account_balance(min * 10).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+        // Withdraw the free Near so that the account cannot pay for a new balance
+        testing_env!(context.attached_deposit(1).build());
+        near_accounts.storage_withdraw(None);
+        let account: AccountId = accounts(1).into();
+        let storage_usage = near_accounts.get_account_checked(&account).storage_usage;
+        assert_eq!(near_accounts.storage_balance_of(accounts(1)).unwrap().available.0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        let amount_unused =
+            ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        assert_eq!(amount_unused, "1000");
+
+        let near_account = near_accounts.get_account_checked(&account);
+        assert_eq!(get_ft_balance(&near_account, &accounts(2).into()), 0);
+        assert_eq!(near_account.info.balances_len(), 0);
+        assert_eq!(near_account.storage_usage, storage_usage);
+    }
+
+    #[test]
+    fn test_on_transfer_auto_registers_from_storage_reserve() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min * 2).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::auto_register::set_auto_register_policy(
+            &mut near_accounts,
+            crate::AutoRegisterPolicy::StorageReserve,
+        );
+        // The policy is paid from the storage reserve and only applies to this instance
+        assert!(near_accounts.storage_reserve < min * 2);
+        let other_accounts = Accounts::<Info>::new_with_prefix(b"other-accounts".to_vec());
+        assert_eq!(
+            crate::auto_register::get_auto_register_policy(&other_accounts),
+            crate::AutoRegisterPolicy::Disabled
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        let account: AccountId = accounts(1).into();
+        // Only tokens in the allowlist are deposited
+        let amount_unused =
+            ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        assert_eq!(amount_unused, "1000");
+        assert!(near_accounts.get_account(&account).is_none());
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        crate::token_policy::add_allowed_tokens(&mut near_accounts, vec![accounts(2).into()]);
+
+        // The sender cannot choose another account to register
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        let msg = serde_json::to_string(&OnTransferOpts { sender_id: accounts(3).into() }).unwrap();
+        let amount_unused = ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), msg);
+        assert_eq!(amount_unused, "1000");
+        assert!(near_accounts.get_account(&accounts(3).into()).is_none());
+
+        let reserve_prior = near_accounts.storage_reserve;
+        let amount_unused =
+            ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        assert_eq!(amount_unused, "0");
+
+        let near_account = near_accounts.get_account_checked(&account);
+        assert_eq!(get_ft_balance(&near_account, &accounts(2).into()), 1000);
+        assert_eq!(near_account.storage_debt, near_account.near_amount);
+        assert_eq!(near_accounts.storage_reserve, reserve_prior - near_account.storage_debt);
+    }
+
+    #[test]
+    fn test_on_transfer_auto_registers_two_senders() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut near_accounts = Accounts::<Info>::new();
+        let min = near_accounts.storage_balance_bounds().min.0;
+
+        testing_env!(context.attached_deposit(min * 4).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::auto_register::set_auto_register_policy(
+            &mut near_accounts,
+            crate::AutoRegisterPolicy::StorageReserve,
+        );
+        crate::token_policy::add_allowed_tokens(&mut near_accounts, vec![accounts(2).into()]);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(0).build());
+        for i in [1, 3] {
+            let amount_unused =
+                ft_on_transfer(&mut near_accounts, accounts(i).into(), 1000.to_string(), "".into());
+            assert_eq!(amount_unused, "0");
+            let near_account = near_accounts.get_account_checked(&accounts(i).into());
+            assert_eq!(get_ft_balance(&near_account, &accounts(2).into()), 1000);
+        }
+    }
+
+    #[test]
+    fn test_balance_transfer_batch() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        let min = near_accounts.storage_balance_bounds().min.0;
+        for i in 3..5 {
+            testing_env!(context.predecessor_account_id(accounts(i)).attached_deposit(min).build());
+            near_accounts.storage_deposit(None, Some(true));
+        }
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::storage_payer::set_storage_payer_policy(
+            &mut near_accounts,
+            crate::StoragePayerPolicy::Sender,
+        );
+
+        let transfer = |recipient: usize, amount: u128| BalanceTransferRequest {
+            recipient: accounts(recipient),
+            token_id: accounts(2),
+            amount: U128(amount),
+        };
+        balance_transfer_batch(
+            &mut near_accounts,
+            vec![transfer(3, 100), transfer(4, 200), transfer(3, 50)],
+            Some("payroll".to_string()),
+        );
+
+        let balance_of = |near_accounts: &Accounts<Info>, i: usize| {
+            get_ft_balance(&near_accounts.get_account_checked(&accounts(i).into()), &tok)
+        };
+        assert_eq!(balance_of(&near_accounts, 0), 650);
+        assert_eq!(balance_of(&near_accounts, 3), 150);
+        assert_eq!(balance_of(&near_accounts, 4), 200);
+        assert_eq!(solvency::get_total_internal_balance(&near_accounts, &tok), 1000);
+
+        // Under the sender policy the caller sponsored the new balances of the recipients
+        for i in 3..5 {
+            let recipient = near_accounts.get_account_checked(&accounts(i).into());
+            assert_eq!(recipient.sponsor_id, Some(account.clone()));
+            assert!(recipient.sponsored_amount > 0);
+            assert_eq!(recipient.near_amount, min + recipient.sponsored_amount);
+        }
+    }
+
+    #[test]
+    fn test_balance_transfer_batch_failing_halfway_changes_nothing() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        let min = near_accounts.storage_balance_bounds().min.0;
+        for i in 3..5 {
+            testing_env!(context.predecessor_account_id(accounts(i)).attached_deposit(min).build());
+            near_accounts.storage_deposit(None, Some(true));
+        }
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(min).build());
+        near_accounts.fund_storage_reserve();
+        testing_env!(context.attached_deposit(1).build());
+        crate::storage_payer::set_storage_payer_policy(
+            &mut near_accounts,
+            crate::StoragePayerPolicy::Sender,
+        );
+
+        // The caller can pay for the first recipient's new balance, but not for the second's as well
+        let balance_storage_cost = get_transfer_batch_storage_costs(
+            &mut near_accounts,
+            &[(accounts(3).into(), vec![(tok.clone(), 100)])],
+        )[0]
+        .1;
+        testing_env!(context.account_balance(min * 100).build());
+        near_accounts.storage_withdraw(None);
+        testing_env!(context.attached_deposit(balance_storage_cost * 3 / 2).build());
+        near_accounts.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(1).build());
+        let transfer = |recipient: usize| BalanceTransferRequest {
+            recipient: accounts(recipient),
+            token_id: accounts(2),
+            amount: U128(100),
+        };
+        let account_of = |near_accounts: &Accounts<Info>, i: usize| {
+            let account = near_accounts.get_account_checked(&accounts(i).into());
+            (get_ft_balance(&account, &tok), account.near_amount, account.storage_usage)
+        };
+        let prior: Vec<_> = [0, 3, 4].iter().map(|i| account_of(&near_accounts, *i)).collect();
+        let storage_prior = env::storage_usage();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            balance_transfer_batch(&mut near_accounts, vec![transfer(3), transfer(4)], None)
+        }));
+        assert!(result.is_err());
+
+        let after: Vec<_> = [0, 3, 4].iter().map(|i| account_of(&near_accounts, *i)).collect();
+        assert_eq!(after, prior);
+        assert_eq!(env::storage_usage(), storage_prior);
+        assert_eq!(solvency::get_total_internal_balance(&near_accounts, &tok), 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_BALANCE")]
+    fn test_balance_transfer_batch_above_total() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, _tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        let min = near_accounts.storage_balance_bounds().min.0;
+        testing_env!(context.predecessor_account_id(accounts(3)).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, Some(true));
+
+        // Each transfer is covered on its own, but not both together
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        let transfer = || BalanceTransferRequest {
+            recipient: accounts(3),
+            token_id: accounts(2),
+            amount: U128(600),
+        };
+        balance_transfer_batch(&mut near_accounts, vec![transfer(), transfer()], None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SELF_TRANSFER")]
+    fn test_balance_transfer_batch_to_caller() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, _tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        let transfer = BalanceTransferRequest {
+            recipient: accounts(0),
+            token_id: accounts(2),
+            amount: U128(100),
+        };
+        balance_transfer_batch(&mut near_accounts, vec![transfer], None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOO_MANY_WITHDRAWALS")]
+    fn test_withdraw_many_above_max() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, _tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        let withdrawals = (0..=MAX_WITHDRAWALS)
+            .map(|_| WithdrawRequest {
+                token_id: accounts(2),
+                amount: U128(10),
+                recipient: None,
+                msg: None,
+            })
+            .collect();
+        withdraw_many(&mut near_accounts, withdrawals);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_BALANCE")]
+    fn test_withdraw_to_call_above_balance() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(1).build());
+        withdraw_to_call(&mut near_accounts, tok, 1001, accounts(3).into(), "".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_GAS")]
+    fn test_balance_transfer_call_without_receiver_gas() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let (account, tok, mut near_accounts, _near_account, mut context) =
+            get_near_accounts(context);
+        ft_on_transfer(&mut near_accounts, account.clone(), 1000.to_string(), "".to_string());
+        let min = near_accounts.storage_balance_bounds().min.0;
+        testing_env!(context.predecessor_account_id(accounts(3)).attached_deposit(min).build());
+        near_accounts.storage_deposit(None, None);
+
+        // Only the gas to resolve the transfer is attached
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .attached_deposit(1)
+            .prepaid_gas(GAS_FOR_RESOLVE_BALANCE_TRANSFER_CALL + GAS_BUFFER)
+            .build());
+        balance_transfer_call(&mut near_accounts, accounts(3).into(), tok, 100, "".to_string());
+    }
 }
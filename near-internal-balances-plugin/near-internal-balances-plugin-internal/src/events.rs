@@ -0,0 +1,146 @@
+use near_account::{emit_event, AccountsError};
+use near_sdk::{serde_json::json, AccountId, Balance};
+
+pub const EVENT_STANDARD: &str = "internal-balances";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+pub(crate) fn emit_deposit(account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+    emit_event(
+        EVENT_STANDARD,
+        EVENT_VERSION,
+        "balance_deposit",
+        json!({
+            "account_id": account_id,
+            "token_id": token_id,
+            "amount": amount.to_string(),
+        }),
+    )
+}
+
+pub(crate) fn emit_withdraw(
+    account_id: &AccountId,
+    receiver_id: &AccountId,
+    token_id: &AccountId,
+    amount: Balance,
+) {
+    emit_event(
+        EVENT_STANDARD,
+        EVENT_VERSION,
+        "balance_withdraw",
+        json!({
+            "account_id": account_id,
+            "receiver_id": receiver_id,
+            "token_id": token_id,
+            "amount": amount.to_string(),
+        }),
+    )
+}
+
+pub(crate) fn emit_transfer(
+    sender_id: &AccountId,
+    receiver_id: &AccountId,
+    token_id: &AccountId,
+    amount: Balance,
+    memo: Option<&String>,
+) {
+    emit_event(
+        EVENT_STANDARD,
+        EVENT_VERSION,
+        "balance_transfer",
+        json!({
+            "sender_id": sender_id,
+            "receiver_id": receiver_id,
+            "token_id": token_id,
+            "amount": amount.to_string(),
+            "memo": memo,
+        }),
+    )
+}
+
+pub(crate) fn emit_refund(account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+    emit_event(
+        EVENT_STANDARD,
+        EVENT_VERSION,
+        "balance_refund",
+        json!({
+            "account_id": account_id,
+            "token_id": token_id,
+            "amount": amount.to_string(),
+        }),
+    )
+}
+
+pub(crate) fn emit_approve(
+    owner_id: &AccountId,
+    spender_id: &AccountId,
+    token_id: &AccountId,
+    amount: Balance,
+) {
+    emit_event(
+        EVENT_STANDARD,
+        EVENT_VERSION,
+        "balance_approve",
+        json!({
+            "owner_id": owner_id,
+            "spender_id": spender_id,
+            "token_id": token_id,
+            "amount": amount.to_string(),
+        }),
+    )
+}
+
+pub(crate) fn emit_insolvency(
+    token_id: &AccountId,
+    total_internal_balance: Balance,
+    contract_balance: Balance,
+) {
+    emit_event(
+        EVENT_STANDARD,
+        EVENT_VERSION,
+        "insolvency",
+        json!({
+            "token_id": token_id,
+            "total_internal_balance": total_internal_balance.to_string(),
+            "contract_balance": contract_balance.to_string(),
+        }),
+    )
+}
+
+/// The contract holds more of the token than the internal balances owe
+pub(crate) fn emit_surplus(
+    token_id: &AccountId,
+    total_internal_balance: Balance,
+    contract_balance: Balance,
+) {
+    emit_event(
+        EVENT_STANDARD,
+        EVENT_VERSION,
+        "surplus",
+        json!({
+            "token_id": token_id,
+            "total_internal_balance": total_internal_balance.to_string(),
+            "contract_balance": contract_balance.to_string(),
+        }),
+    )
+}
+
+/// A deposit which could not be credited and is returned to the token contract as unused
+pub(crate) fn emit_deposit_refund(
+    account_id: &AccountId,
+    token_id: &AccountId,
+    amount: &str,
+    reason: &AccountsError,
+) {
+    emit_event(
+        EVENT_STANDARD,
+        EVENT_VERSION,
+        "balance_deposit_refund",
+        json!({
+            "account_id": account_id,
+            "token_id": token_id,
+            "amount": amount,
+            "code": reason.code(),
+            "reason": reason.to_string(),
+        }),
+    )
+}
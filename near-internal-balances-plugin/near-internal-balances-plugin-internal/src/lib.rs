@@ -4,14 +4,24 @@ use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize},
     collections::UnorderedMap,
     env::{self},
-    json_types::{ValidAccountId, U128},
+    json_types::{ValidAccountId, U128, U64},
     log,
     serde::{Deserialize, Serialize},
-    AccountId, Balance, Promise,
+    AccountId, Balance, Promise, PromiseOrValue,
 };
 
+mod events;
+
+pub mod allowance;
+pub mod auto_register;
 pub mod core_impl;
 mod macros;
+pub mod solvency;
+mod state;
+pub mod storage_payer;
+pub mod token_policy;
+#[cfg(test)]
+pub(crate) mod test_utils;
 pub use macros::*;
 
 pub trait NearFTInternalBalance:
@@ -26,9 +36,116 @@ pub struct OnTransferOpts {
     pub sender_id: AccountId,
 }
 
+/// One transfer of `balance_transfer_batch`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BalanceTransferRequest {
+    pub recipient: ValidAccountId,
+    pub token_id: ValidAccountId,
+    pub amount: U128,
+}
+
+/// One withdrawal of `withdraw_many`
+///
+/// With a `msg` the tokens are sent with `ft_transfer_call`, otherwise with `ft_transfer`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawRequest {
+    pub token_id: ValidAccountId,
+    pub amount: U128,
+    /// Defaults to the caller
+    pub recipient: Option<ValidAccountId>,
+    pub msg: Option<String>,
+}
+
+/// The outcome of one withdrawal of `withdraw_many`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawOutcome {
+    pub token_id: AccountId,
+    pub amount: U128,
+    /// The amount which left the contract, the rest is back in the caller's internal balance
+    pub amount_used: U128,
+    /// Whether the withdrawal was resolved, if not the caller's internal balance may need to be checked
+    pub resolved: bool,
+}
+
+/// Who pays for the storage of a new balance when a transfer credits another account
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum StoragePayerPolicy {
+    /// The account receiving the balance pays
+    #[default]
+    Recipient,
+    /// The account sending the balance pays, if it is registered
+    Sender,
+    /// The contract pays
+    Contract,
+}
+
+/// How `ft_on_transfer` registers an account on its first deposit, managed by the owner
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AutoRegisterPolicy {
+    /// Deposits for unregistered accounts are refunded
+    #[default]
+    Disabled,
+    /// The storage is lent from the contract's storage reserve and repaid on the account's next deposit,
+    /// only for senders depositing to themselves a token in the allowlist
+    StorageReserve,
+    /// The storage is paid with Near parked for the account, see `Accounts::park_storage_deposit`
+    ParkedDeposit,
+}
+
+/// Which tokens can be deposited with `ft_on_transfer`, managed by the owner
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TokenPolicy {
+    /// Any token can be deposited
+    #[default]
+    AllowAll,
+    /// Only the tokens in the allowlist can be deposited
+    Allowlist,
+    /// Any token but the tokens in the denylist can be deposited
+    Denylist,
+}
+
 pub trait BalanceInfo {
     fn get_balance(&self, token_id: &AccountId) -> Balance;
     fn set_balance(&mut self, token_id: &AccountId, balance: Balance);
+    /// Remove the balance entry of a token, freeing its storage
+    fn remove_balance(&mut self, token_id: &AccountId);
+    /// Get the number of tokens the account has a balance entry for
+    fn balances_len(&self) -> u64;
+    /// Get up to `limit` token balances starting at `from_index`
+    fn get_balances(&self, from_index: u64, limit: u64) -> Vec<(AccountId, Balance)>;
+}
+
+/// Allowances given by an account to spend its balances, stored in the account's info
+///
+/// Implemented by `impl_near_balance_plugin` when it is given the allowance map,
+/// an `UnorderedMap<(AccountId, AccountId), Balance>` keyed by the spender and the token
+pub trait AllowanceInfo {
+    fn get_allowance(&self, spender_id: &AccountId, token_id: &AccountId) -> Balance;
+    /// Set the allowance, an allowance of 0 removes the entry and frees its storage
+    fn set_allowance(&mut self, spender_id: &AccountId, token_id: &AccountId, allowance: Balance);
+}
+
+/// The maximum number of balances returned by `get_ft_balances`, so that views stay within the gas limit
+pub const MAX_BALANCES_PAGE_SIZE: u64 = 100;
+
+/// A token held by an account and its internal balance
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenBalance {
+    pub token_id: AccountId,
+    pub balance: U128,
 }
 
 pub trait SudoInternalBalanceFungibleToken {
@@ -50,9 +167,51 @@ pub trait SudoInternalBalanceFungibleToken {
     );
 }
 
+/// Implemented by contracts which receive internal balances with `balance_transfer_call`
+pub trait InternalBalanceReceiver {
+    /// Called once `amount` is credited to the receiver's internal balance,
+    /// returns the amount which was not used and is refunded to the sender
+    fn on_internal_balance_transfer(
+        &mut self,
+        sender_id: ValidAccountId,
+        token_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
+}
+
+/// Delegated spending of internal balances, see `AllowanceInfo`
+pub trait InternalBalanceAllowanceHandlers {
+    /// Allow `spender` to spend up to `amount` of the caller's token, replacing any previous allowance
+    fn approve(&mut self, spender: ValidAccountId, token_id: ValidAccountId, amount: U128);
+
+    fn allowance(
+        &self,
+        owner: ValidAccountId,
+        spender: ValidAccountId,
+        token_id: ValidAccountId,
+    ) -> U128;
+
+    /// Transfer `amount` of the owner's token to `recipient`, spending the caller's allowance
+    fn transfer_from(
+        &mut self,
+        owner: ValidAccountId,
+        recipient: ValidAccountId,
+        token_id: ValidAccountId,
+        amount: U128,
+    );
+}
+
 pub trait InternalBalanceFungibleTokenHandlers {
     fn ft_on_transfer(&mut self, sender_id: String, amount: String, msg: String) -> String;
     fn get_ft_balance(&self, account_id: ValidAccountId, token_id: ValidAccountId) -> U128;
+    /// List the balances of an account, at most `MAX_BALANCES_PAGE_SIZE` per page
+    fn get_ft_balances(
+        &self,
+        account_id: ValidAccountId,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<TokenBalance>;
     fn resolve_internal_ft_transfer_call(
         &mut self,
         account_id: ValidAccountId,
@@ -68,6 +227,25 @@ pub trait InternalBalanceFungibleTokenHandlers {
         msg: Option<String>,
     );
 
+    /// Withdraw with `ft_transfer_call` so that the recipient can act on the tokens with `msg`,
+    /// the amount which the recipient does not use goes back to the caller's internal balance
+    fn withdraw_to_call(
+        &mut self,
+        token_id: ValidAccountId,
+        amount: U128,
+        recipient: ValidAccountId,
+        msg: String,
+    );
+
+    /// Withdraw several tokens at once, each withdrawal is resolved independently
+    fn withdraw_many(&mut self, withdrawals: Vec<WithdrawRequest>);
+
+    fn resolve_withdraw_many(
+        &mut self,
+        token_ids: Vec<ValidAccountId>,
+        amounts: Vec<U128>,
+    ) -> Vec<WithdrawOutcome>;
+
     fn balance_transfer(
         &mut self,
         recipient: ValidAccountId,
@@ -75,4 +253,68 @@ pub trait InternalBalanceFungibleTokenHandlers {
         amount: U128,
         message: Option<String>,
     );
+
+    /// Transfer a balance and call `on_internal_balance_transfer` on the recipient,
+    /// the amount which the recipient does not use goes back to the caller
+    fn balance_transfer_call(
+        &mut self,
+        recipient: ValidAccountId,
+        token_id: ValidAccountId,
+        amount: U128,
+        msg: String,
+    );
+
+    fn resolve_balance_transfer_call(
+        &mut self,
+        sender_id: ValidAccountId,
+        recipient: ValidAccountId,
+        token_id: ValidAccountId,
+        amount: U128,
+    ) -> U128;
+
+    /// Get the sum of all internal balances of a token
+    fn get_total_internal_balance(&self, token_id: ValidAccountId) -> U128;
+
+    /// Compare the contract's balance on the token contract with the token's total internal balance
+    fn verify_solvency(&mut self, token_id: ValidAccountId);
+
+    fn resolve_verify_solvency(&mut self, token_id: ValidAccountId) -> solvency::SolvencyReport;
+
+    /// Transfer balances to several recipients at once, either all transfers succeed or none do
+    fn balance_transfer_batch(
+        &mut self,
+        transfers: Vec<BalanceTransferRequest>,
+        memo: Option<String>,
+    );
+
+    /// Set who pays for the storage of new balances created by transfers, only callable by the owner
+    fn set_storage_payer_policy(&mut self, policy: StoragePayerPolicy);
+
+    fn get_storage_payer_policy(&self) -> StoragePayerPolicy;
+
+    /// Set how accounts are registered on their first deposit, only callable by the owner
+    fn set_auto_register_policy(&mut self, policy: AutoRegisterPolicy);
+
+    fn get_auto_register_policy(&self) -> AutoRegisterPolicy;
+
+    /// Set which tokens can be deposited, only callable by the owner
+    fn set_token_policy(&mut self, policy: TokenPolicy);
+
+    fn get_token_policy(&self) -> TokenPolicy;
+
+    /// Add tokens to the allowlist of the token policy, only callable by the owner
+    fn add_allowed_tokens(&mut self, token_ids: Vec<ValidAccountId>);
+
+    /// Remove tokens from the allowlist of the token policy, only callable by the owner
+    fn remove_allowed_tokens(&mut self, token_ids: Vec<ValidAccountId>);
+
+    fn get_allowed_tokens(&self) -> Vec<AccountId>;
+
+    /// Add tokens to the denylist of the token policy, only callable by the owner
+    fn add_denied_tokens(&mut self, token_ids: Vec<ValidAccountId>);
+
+    /// Remove tokens from the denylist of the token policy, only callable by the owner
+    fn remove_denied_tokens(&mut self, token_ids: Vec<ValidAccountId>);
+
+    fn get_denied_tokens(&self) -> Vec<AccountId>;
 }
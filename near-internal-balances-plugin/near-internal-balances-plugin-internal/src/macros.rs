@@ -1,6 +1,12 @@
 #[macro_export]
 macro_rules! impl_near_balance_plugin {
-    ($contract_struct: ident, $accounts: ident, $info_struct: ident, $balance_map: ident) => {
+    (
+        $contract_struct: ident,
+        $accounts: ident,
+        $info_struct: ident,
+        $balance_map: ident
+        $(, $allowance_map: ident)?
+    ) => {
         use $crate::{
             BalanceInfo, InternalBalanceFungibleTokenHandlers, NearFTInternalBalance,
             SudoInternalBalanceFungibleToken,
@@ -14,6 +20,37 @@ macro_rules! impl_near_balance_plugin {
             fn set_balance(&mut self, token_id: &AccountId, balance: Balance) {
                 self.$balance_map.insert(token_id, &balance);
             }
+
+            fn remove_balance(&mut self, token_id: &AccountId) {
+                self.$balance_map.remove(token_id);
+            }
+
+            fn balances_len(&self) -> u64 {
+                self.$balance_map.len()
+            }
+
+            fn get_balances(&self, from_index: u64, limit: u64) -> Vec<(AccountId, Balance)> {
+                let keys = self.$balance_map.keys_as_vector();
+                let values = self.$balance_map.values_as_vector();
+                let to_index = from_index.saturating_add(limit).min(keys.len());
+                (from_index..to_index)
+                    .map(|index| (keys.get(index).unwrap(), values.get(index).unwrap()))
+                    .collect()
+            }
+        }
+
+        impl $crate::core_impl::DefaultAccountInfo for $info_struct {
+            fn can_unregister(&self) -> bool {
+                self.$balance_map.iter().all(|(_, balance)| balance == 0)
+            }
+
+            fn on_unregister(&mut self, accounts: &mut $crate::core_impl::Accounts<Self>) {
+                for (token_id, balance) in self.$balance_map.iter() {
+                    $crate::solvency::decrease_total(accounts, &token_id, balance);
+                }
+                self.$balance_map.clear();
+                $(self.$allowance_map.clear();)?
+            }
         }
 
         impl $crate::core_impl::AccountInfoTrait for $info_struct {
@@ -95,6 +132,20 @@ macro_rules! impl_near_balance_plugin {
                 U128::from(bal)
             }
 
+            fn get_ft_balances(
+                &self,
+                account_id: ValidAccountId,
+                from_index: Option<near_sdk::json_types::U64>,
+                limit: Option<near_sdk::json_types::U64>,
+            ) -> Vec<$crate::TokenBalance> {
+                $crate::core_impl::get_ft_balances(
+                    &self.$accounts,
+                    &account_id.into(),
+                    from_index.map(|index| index.into()),
+                    limit.map(|limit| limit.into()),
+                )
+            }
+
             /// A private contract function which resolves the ft transfer by updating the amount used in the balances
             /// @returns the amount used
             #[private]
@@ -130,6 +181,51 @@ macro_rules! impl_near_balance_plugin {
                 )
             }
 
+            #[payable]
+            fn balance_transfer_call(
+                &mut self,
+                recipient: ValidAccountId,
+                token_id: ValidAccountId,
+                amount: U128,
+                msg: String,
+            ) {
+                $crate::core_impl::balance_transfer_call(
+                    &mut self.$accounts,
+                    recipient.into(),
+                    token_id.into(),
+                    amount.into(),
+                    msg,
+                )
+            }
+
+            /// A private contract function which refunds what the recipient of `balance_transfer_call` did not use
+            /// @returns the amount used
+            #[private]
+            fn resolve_balance_transfer_call(
+                &mut self,
+                sender_id: ValidAccountId,
+                recipient: ValidAccountId,
+                token_id: ValidAccountId,
+                amount: U128,
+            ) -> U128 {
+                $crate::core_impl::resolve_balance_transfer_call(
+                    &mut self.$accounts,
+                    &sender_id.into(),
+                    &recipient.into(),
+                    &token_id.into(),
+                    amount,
+                )
+            }
+
+            #[payable]
+            fn balance_transfer_batch(
+                &mut self,
+                transfers: Vec<$crate::BalanceTransferRequest>,
+                memo: Option<String>,
+            ) {
+                $crate::core_impl::balance_transfer_batch(&mut self.$accounts, transfers, memo)
+            }
+
             #[payable]
             fn withdraw_to(
                 &mut self,
@@ -146,7 +242,199 @@ macro_rules! impl_near_balance_plugin {
                     msg,
                 )
             }
+
+            fn get_total_internal_balance(&self, token_id: ValidAccountId) -> U128 {
+                $crate::solvency::get_total_internal_balance(&self.$accounts, &token_id.into()).into()
+            }
+
+            fn verify_solvency(&mut self, token_id: ValidAccountId) {
+                $crate::solvency::verify_solvency(token_id.into())
+            }
+
+            /// A private contract function which reports the solvency from the result of `ft_balance_of`
+            #[private]
+            fn resolve_verify_solvency(
+                &mut self,
+                token_id: ValidAccountId,
+            ) -> $crate::solvency::SolvencyReport {
+                $crate::solvency::resolve_verify_solvency(&self.$accounts, token_id.into())
+            }
+
+            #[payable]
+            fn withdraw_to_call(
+                &mut self,
+                token_id: ValidAccountId,
+                amount: U128,
+                recipient: ValidAccountId,
+                msg: String,
+            ) {
+                $crate::core_impl::withdraw_to_call(
+                    &mut self.$accounts,
+                    token_id.into(),
+                    amount.into(),
+                    recipient.into(),
+                    msg,
+                )
+            }
+
+            #[payable]
+            fn withdraw_many(&mut self, withdrawals: Vec<$crate::WithdrawRequest>) {
+                $crate::core_impl::withdraw_many(&mut self.$accounts, withdrawals)
+            }
+
+            /// A private contract function which collects the outcomes of `withdraw_many`
+            #[private]
+            fn resolve_withdraw_many(
+                &mut self,
+                token_ids: Vec<ValidAccountId>,
+                amounts: Vec<U128>,
+            ) -> Vec<$crate::WithdrawOutcome> {
+                $crate::core_impl::resolve_withdraw_many(
+                    token_ids.into_iter().map(|token_id| token_id.into()).collect(),
+                    amounts,
+                )
+            }
+
+            #[payable]
+            fn set_storage_payer_policy(&mut self, policy: $crate::StoragePayerPolicy) {
+                $crate::storage_payer::set_storage_payer_policy(&mut self.$accounts, policy)
+            }
+
+            fn get_storage_payer_policy(&self) -> $crate::StoragePayerPolicy {
+                $crate::storage_payer::get_storage_payer_policy(&self.$accounts)
+            }
+
+            #[payable]
+            fn set_auto_register_policy(&mut self, policy: $crate::AutoRegisterPolicy) {
+                $crate::auto_register::set_auto_register_policy(&mut self.$accounts, policy)
+            }
+
+            fn get_auto_register_policy(&self) -> $crate::AutoRegisterPolicy {
+                $crate::auto_register::get_auto_register_policy(&self.$accounts)
+            }
+
+            #[payable]
+            fn set_token_policy(&mut self, policy: $crate::TokenPolicy) {
+                $crate::token_policy::set_token_policy(&mut self.$accounts, policy)
+            }
+
+            fn get_token_policy(&self) -> $crate::TokenPolicy {
+                $crate::token_policy::get_token_policy(&self.$accounts)
+            }
+
+            #[payable]
+            fn add_allowed_tokens(&mut self, token_ids: Vec<ValidAccountId>) {
+                $crate::token_policy::add_allowed_tokens(
+                    &mut self.$accounts,
+                    token_ids.into_iter().map(|token_id| token_id.into()).collect(),
+                )
+            }
+
+            #[payable]
+            fn remove_allowed_tokens(&mut self, token_ids: Vec<ValidAccountId>) {
+                $crate::token_policy::remove_allowed_tokens(
+                    &mut self.$accounts,
+                    token_ids.into_iter().map(|token_id| token_id.into()).collect(),
+                )
+            }
+
+            fn get_allowed_tokens(&self) -> Vec<AccountId> {
+                $crate::token_policy::get_allowed_tokens(&self.$accounts)
+            }
+
+            #[payable]
+            fn add_denied_tokens(&mut self, token_ids: Vec<ValidAccountId>) {
+                $crate::token_policy::add_denied_tokens(
+                    &mut self.$accounts,
+                    token_ids.into_iter().map(|token_id| token_id.into()).collect(),
+                )
+            }
+
+            #[payable]
+            fn remove_denied_tokens(&mut self, token_ids: Vec<ValidAccountId>) {
+                $crate::token_policy::remove_denied_tokens(
+                    &mut self.$accounts,
+                    token_ids.into_iter().map(|token_id| token_id.into()).collect(),
+                )
+            }
+
+            fn get_denied_tokens(&self) -> Vec<AccountId> {
+                $crate::token_policy::get_denied_tokens(&self.$accounts)
+            }
         }
         impl NearFTInternalBalance for $contract_struct {}
+
+        $(
+            use $crate::InternalBalanceAllowanceHandlers;
+
+            impl $crate::AllowanceInfo for $info_struct {
+                fn get_allowance(&self, spender_id: &AccountId, token_id: &AccountId) -> Balance {
+                    self.$allowance_map.get(&(spender_id.clone(), token_id.clone())).unwrap_or(0)
+                }
+
+                fn set_allowance(
+                    &mut self,
+                    spender_id: &AccountId,
+                    token_id: &AccountId,
+                    allowance: Balance,
+                ) {
+                    let key = (spender_id.clone(), token_id.clone());
+                    if allowance == 0 {
+                        self.$allowance_map.remove(&key);
+                    } else {
+                        self.$allowance_map.insert(&key, &allowance);
+                    }
+                }
+            }
+
+            #[near_bindgen]
+            impl InternalBalanceAllowanceHandlers for $contract_struct {
+                #[payable]
+                fn approve(
+                    &mut self,
+                    spender: ValidAccountId,
+                    token_id: ValidAccountId,
+                    amount: U128,
+                ) {
+                    $crate::allowance::approve(
+                        &mut self.$accounts,
+                        &spender.into(),
+                        &token_id.into(),
+                        amount.into(),
+                    )
+                }
+
+                fn allowance(
+                    &self,
+                    owner: ValidAccountId,
+                    spender: ValidAccountId,
+                    token_id: ValidAccountId,
+                ) -> U128 {
+                    U128::from($crate::allowance::get_allowance(
+                        &self.$accounts,
+                        &owner.into(),
+                        &spender.into(),
+                        &token_id.into(),
+                    ))
+                }
+
+                #[payable]
+                fn transfer_from(
+                    &mut self,
+                    owner: ValidAccountId,
+                    recipient: ValidAccountId,
+                    token_id: ValidAccountId,
+                    amount: U128,
+                ) {
+                    $crate::allowance::transfer_from(
+                        &mut self.$accounts,
+                        &owner.into(),
+                        &recipient.into(),
+                        &token_id.into(),
+                        amount.into(),
+                    )
+                }
+            }
+        )?
     };
 }
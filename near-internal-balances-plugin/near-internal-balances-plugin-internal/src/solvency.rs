@@ -0,0 +1,145 @@
+use near_account::{Accounts, AccountsError, UnwrapOrPanic};
+use near_sdk::{
+    collections::LookupMap,
+    env,
+    json_types::U128,
+    serde::{Deserialize, Serialize},
+    serde_json::{self, json},
+    AccountId, Balance, Gas,
+};
+
+use crate::{core_impl::AccountInfoTrait, events};
+
+const TOTALS_SUFFIX: &[u8] = b"-totals";
+
+const FT_BALANCE_OF_METHOD_NAME: &str = "ft_balance_of";
+const RESOLVE_SOLVENCY_NAME: &str = "resolve_verify_solvency";
+
+const GAS_FOR_FT_BALANCE_OF: Gas = 5_000_000_000_000;
+const GAS_FOR_RESOLVE_SOLVENCY: Gas = 5_000_000_000_000;
+
+/// The internal balances owed for a token compared to what the contract holds on the token contract
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SolvencyReport {
+    pub token_id: AccountId,
+    /// The sum of all internal balances of the token
+    pub total_internal_balance: U128,
+    /// The contract's balance on the token contract
+    pub contract_balance: U128,
+    /// How much the contract's balance falls short of the internal balances
+    pub shortfall: U128,
+    /// How much the contract's balance exceeds the internal balances, i.e. tokens sent without `ft_transfer_call`
+    pub surplus: U128,
+    pub is_solvent: bool,
+}
+
+/// The per-token totals live under the prefix of the accounts, so that each plugin instance has its own
+///
+/// A token's total is written within the storage check of the balance which changes it,
+/// so the first depositor of a token pays for the storage of its total
+fn totals<Info: AccountInfoTrait>(accounts: &Accounts<Info>) -> LookupMap<AccountId, Balance> {
+    LookupMap::new(accounts.storage_key(TOTALS_SUFFIX))
+}
+
+/// Get the sum of all internal balances of a token
+pub fn get_total_internal_balance<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    token_id: &AccountId,
+) -> Balance {
+    totals(accounts).get(token_id).unwrap_or(0)
+}
+
+pub(crate) fn increase_total<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    token_id: &AccountId,
+    amount: Balance,
+) {
+    let total = get_total_internal_balance(accounts, token_id) + amount;
+    totals(accounts).insert(token_id, &total);
+}
+
+/// Public for `impl_near_balance_plugin`, which decreases the totals when an account unregisters
+#[doc(hidden)]
+pub fn decrease_total<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    token_id: &AccountId,
+    amount: Balance,
+) {
+    let total = get_total_internal_balance(accounts, token_id).saturating_sub(amount);
+    totals(accounts).insert(token_id, &total);
+}
+
+/// Undo `increase_total` after the storage check of the balance failed, removing a total which was not there before
+pub(crate) fn revert_increase_total<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    token_id: &AccountId,
+    amount: Balance,
+) {
+    let mut totals = totals(accounts);
+    match totals.get(token_id) {
+        Some(total) if total == amount => totals.remove(token_id),
+        Some(total) => totals.insert(token_id, &(total - amount)),
+        None => None,
+    };
+}
+
+/// Query the contract's balance on the token contract and compare it to the token's total internal balance
+///
+/// The report is returned by `resolve_verify_solvency`
+pub fn verify_solvency(token_id: AccountId) {
+    let args = json!({ "account_id": env::current_account_id() }).to_string();
+    let balance_of = env::promise_create(
+        token_id.clone(),
+        FT_BALANCE_OF_METHOD_NAME.as_bytes(),
+        args.as_bytes(),
+        0,
+        GAS_FOR_FT_BALANCE_OF,
+    );
+    let resolve_args = json!({ "token_id": token_id }).to_string();
+    let resolve = env::promise_then(
+        balance_of,
+        env::current_account_id(),
+        RESOLVE_SOLVENCY_NAME.as_bytes(),
+        resolve_args.as_bytes(),
+        0,
+        GAS_FOR_RESOLVE_SOLVENCY,
+    );
+    env::promise_return(resolve);
+}
+
+/// Build the solvency report from the result of `ft_balance_of`
+///
+/// Any difference between the contract's balance and the total internal balance is emitted as an event
+pub fn resolve_verify_solvency<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    token_id: AccountId,
+) -> SolvencyReport {
+    let contract_balance: U128 = near_sdk::utils::promise_result_as_success()
+        .ok_or_else(|| AccountsError::InvalidPromiseResult {
+            reason: format!("ft_balance_of failed on {}", token_id),
+        })
+        .and_then(|data| {
+            serde_json::from_slice(&data).map_err(|e| AccountsError::InvalidPromiseResult {
+                reason: format!("Failed to deserialize ft_balance_of result: {}", e),
+            })
+        })
+        .unwrap_or_panic();
+
+    let total_internal_balance = get_total_internal_balance(accounts, &token_id);
+    let shortfall = total_internal_balance.saturating_sub(contract_balance.0);
+    let surplus = contract_balance.0.saturating_sub(total_internal_balance);
+    if shortfall != 0 {
+        events::emit_insolvency(&token_id, total_internal_balance, contract_balance.0);
+    } else if surplus != 0 {
+        events::emit_surplus(&token_id, total_internal_balance, contract_balance.0);
+    }
+    SolvencyReport {
+        token_id,
+        total_internal_balance: U128::from(total_internal_balance),
+        contract_balance,
+        shortfall: U128::from(shortfall),
+        surplus: U128::from(surplus),
+        is_solvent: shortfall == 0,
+    }
+}
@@ -0,0 +1,51 @@
+use near_account::{Accounts, AccountsError, AccountsResult};
+use near_sdk::{
+    borsh::{BorshDeserialize, BorshSerialize},
+    env,
+};
+
+use crate::core_impl::AccountInfoTrait;
+
+/// Read the plugin's state stored under the prefix of the accounts and `suffix`, `None` if it was never written
+pub(crate) fn read<Info: AccountInfoTrait, T: BorshDeserialize>(
+    accounts: &Accounts<Info>,
+    suffix: &[u8],
+) -> AccountsResult<Option<T>> {
+    env::storage_read(&accounts.storage_key(suffix))
+        .map(|value| {
+            T::try_from_slice(&value).map_err(|e| AccountsError::InvalidState {
+                reason: format!("Failed to deserialize {}: {}", String::from_utf8_lossy(suffix), e),
+            })
+        })
+        .transpose()
+}
+
+/// Update the plugin's state stored under `suffix`, starting from `default` if it was never written
+///
+/// The state is owned by the contract, so the change in storage, including any collection updated
+/// by `update`, is paid from the storage reserve
+pub(crate) fn update<Info, T, D, F>(
+    accounts: &mut Accounts<Info>,
+    suffix: &[u8],
+    default: D,
+    update: F,
+) -> AccountsResult<()>
+where
+    Info: AccountInfoTrait,
+    T: BorshSerialize + BorshDeserialize,
+    D: FnOnce() -> T,
+    F: FnOnce(&mut T),
+{
+    let mut value = read(accounts, suffix)?.unwrap_or_else(default);
+    let key = accounts.storage_key(suffix);
+    accounts
+        .try_pay_from_storage_reserve(|_| {
+            update(&mut value);
+            let value = value.try_to_vec().map_err(|e| AccountsError::InvalidState {
+                reason: format!("Failed to serialize {}: {}", String::from_utf8_lossy(suffix), e),
+            })?;
+            env::storage_write(&key, &value);
+            Ok(())
+        })
+        .and_then(|ret| ret)
+}
@@ -0,0 +1,39 @@
+use near_account::{assert_one_yocto, Accounts, StoragePayer, UnwrapOrPanic};
+use near_sdk::AccountId;
+
+use crate::{core_impl::AccountInfoTrait, state, StoragePayerPolicy};
+
+const STORAGE_PAYER_POLICY_SUFFIX: &[u8] = b"-storage-payer";
+
+pub fn get_storage_payer_policy<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+) -> StoragePayerPolicy {
+    state::read(accounts, STORAGE_PAYER_POLICY_SUFFIX).unwrap_or_panic().unwrap_or_default()
+}
+
+/// Set the storage payer policy, the storage is paid from the storage reserve
+pub fn set_storage_payer_policy<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    policy: StoragePayerPolicy,
+) {
+    assert_one_yocto();
+    accounts.assert_owner();
+    state::update(accounts, STORAGE_PAYER_POLICY_SUFFIX, StoragePayerPolicy::default, |current| {
+        *current = policy
+    })
+    .unwrap_or_panic();
+}
+
+/// Get who pays for crediting a balance sent by `sender_id` under the current policy
+///
+/// See `Accounts::check_storage_paid_by` for when the recipient pays instead
+pub fn get_storage_payer<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    sender_id: &AccountId,
+) -> StoragePayer {
+    match get_storage_payer_policy(accounts) {
+        StoragePayerPolicy::Recipient => StoragePayer::Account,
+        StoragePayerPolicy::Sender => StoragePayer::Sponsor(sender_id.clone()),
+        StoragePayerPolicy::Contract => StoragePayer::Contract,
+    }
+}
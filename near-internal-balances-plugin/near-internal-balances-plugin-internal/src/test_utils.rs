@@ -0,0 +1,103 @@
+use std::convert::TryFrom;
+
+use near_account::{Account, AccountInfoTrait as DefaultAccountInfo, Accounts, NewInfo};
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
+use near_sdk::json_types::ValidAccountId;
+use near_sdk::test_utils::{accounts, VMContextBuilder};
+use near_sdk::testing_env;
+use near_sdk::{AccountId, Balance, MockedBlockchain};
+
+use crate::{core_impl::AccountInfoTrait, AllowanceInfo, BalanceInfo};
+
+pub(crate) const INIT_ACCOUNT_BAL: u128 = 10_000;
+
+/// The account info shared by the plugin's tests, with balances and allowances
+#[derive(BorshSerialize, BorshDeserialize)]
+pub(crate) struct Info {
+    pub internal_balance: UnorderedMap<AccountId, Balance>,
+    pub allowances: UnorderedMap<(AccountId, AccountId), Balance>,
+}
+
+impl NewInfo for Info {
+    fn default_from_account_id(account_id: AccountId) -> Self {
+        Self {
+            internal_balance: UnorderedMap::new(format!("{}-bals-i", &account_id).as_bytes()),
+            allowances: UnorderedMap::new(format!("{}-allow-i", &account_id).as_bytes()),
+        }
+    }
+}
+
+impl DefaultAccountInfo for Info {}
+
+impl AccountInfoTrait for Info {}
+
+impl BalanceInfo for Info {
+    fn get_balance(&self, token_id: &AccountId) -> Balance {
+        self.internal_balance.get(token_id).unwrap_or(0)
+    }
+
+    fn set_balance(&mut self, token_id: &AccountId, balance: Balance) {
+        self.internal_balance.insert(token_id, &balance);
+    }
+
+    fn remove_balance(&mut self, token_id: &AccountId) {
+        self.internal_balance.remove(token_id);
+    }
+
+    fn balances_len(&self) -> u64 {
+        self.internal_balance.len()
+    }
+
+    fn get_balances(&self, from_index: u64, limit: u64) -> Vec<(AccountId, Balance)> {
+        let keys = self.internal_balance.keys_as_vector();
+        let values = self.internal_balance.values_as_vector();
+        let to_index = from_index.saturating_add(limit).min(keys.len());
+        (from_index..to_index)
+            .map(|index| (keys.get(index).unwrap(), values.get(index).unwrap()))
+            .collect()
+    }
+}
+
+impl AllowanceInfo for Info {
+    fn get_allowance(&self, spender_id: &AccountId, token_id: &AccountId) -> Balance {
+        self.allowances.get(&(spender_id.clone(), token_id.clone())).unwrap_or(0)
+    }
+
+    fn set_allowance(&mut self, spender_id: &AccountId, token_id: &AccountId, allowance: Balance) {
+        let key = (spender_id.clone(), token_id.clone());
+        if allowance == 0 {
+            self.allowances.remove(&key);
+        } else {
+            self.allowances.insert(&key, &allowance);
+        }
+    }
+}
+
+/// Register account 0 with 10 times the minimum storage balance, token account 2 is used as the token
+pub(crate) fn get_near_accounts(
+    mut context: VMContextBuilder,
+) -> (AccountId, AccountId, Accounts<Info>, Account<Info>, VMContextBuilder) {
+    let mut near_accounts = Accounts::<Info>::new();
+    let account: AccountId = accounts(0).into();
+    let tok: AccountId = accounts(2).into();
+    let min = near_accounts.storage_balance_bounds().min.0;
+    testing_env!(context.attached_deposit(min * 10).build());
+    near_accounts.storage_deposit(Some(ValidAccountId::try_from(account.clone()).unwrap()), None);
+    testing_env!(context.attached_deposit(1).build());
+    let near_account = near_accounts.get_account_checked(&account);
+
+    (account, tok, near_accounts, near_account, context)
+}
+
+// mock the context for testing, notice "signer_account_id" that was accessed above from env::
+pub(crate) fn get_context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id)
+        .account_balance(INIT_ACCOUNT_BAL);
+    builder
+}
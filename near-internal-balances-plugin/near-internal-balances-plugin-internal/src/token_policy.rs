@@ -0,0 +1,130 @@
+use near_account::{assert_one_yocto, Accounts, UnwrapOrPanic};
+use near_sdk::{collections::UnorderedSet, AccountId};
+
+use crate::{core_impl::AccountInfoTrait, state, TokenPolicy};
+
+const TOKEN_POLICY_SUFFIX: &[u8] = b"-token-policy";
+const TOKEN_ALLOWLIST_SUFFIX: &[u8] = b"-token-allowlist";
+const TOKEN_DENYLIST_SUFFIX: &[u8] = b"-token-denylist";
+
+/// The allowlist and the denylist are kept apart so that switching the policy does not change either list
+///
+/// The set is stored under `suffix` as its length is not persisted by the set itself
+fn read_token_list<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    suffix: &[u8],
+) -> UnorderedSet<AccountId> {
+    state::read(accounts, suffix)
+        .unwrap_or_panic()
+        .unwrap_or_else(|| new_token_list(accounts, suffix))
+}
+
+fn new_token_list<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    suffix: &[u8],
+) -> UnorderedSet<AccountId> {
+    UnorderedSet::new(accounts.storage_key(&[suffix, b"-"].concat()))
+}
+
+pub fn get_token_policy<Info: AccountInfoTrait>(accounts: &Accounts<Info>) -> TokenPolicy {
+    state::read(accounts, TOKEN_POLICY_SUFFIX).unwrap_or_panic().unwrap_or_default()
+}
+
+/// Set the token policy, the storage is paid from the storage reserve
+pub fn set_token_policy<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    policy: TokenPolicy,
+) {
+    assert_one_yocto();
+    accounts.assert_owner();
+    state::update(accounts, TOKEN_POLICY_SUFFIX, TokenPolicy::default, |current| *current = policy)
+        .unwrap_or_panic();
+}
+
+pub fn get_allowed_tokens<Info: AccountInfoTrait>(accounts: &Accounts<Info>) -> Vec<AccountId> {
+    read_token_list(accounts, TOKEN_ALLOWLIST_SUFFIX).to_vec()
+}
+
+pub fn add_allowed_tokens<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    token_ids: Vec<AccountId>,
+) {
+    update_token_list(accounts, TOKEN_ALLOWLIST_SUFFIX, token_ids, true);
+}
+
+pub fn remove_allowed_tokens<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    token_ids: Vec<AccountId>,
+) {
+    update_token_list(accounts, TOKEN_ALLOWLIST_SUFFIX, token_ids, false);
+}
+
+pub fn get_denied_tokens<Info: AccountInfoTrait>(accounts: &Accounts<Info>) -> Vec<AccountId> {
+    read_token_list(accounts, TOKEN_DENYLIST_SUFFIX).to_vec()
+}
+
+pub fn add_denied_tokens<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    token_ids: Vec<AccountId>,
+) {
+    update_token_list(accounts, TOKEN_DENYLIST_SUFFIX, token_ids, true);
+}
+
+pub fn remove_denied_tokens<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    token_ids: Vec<AccountId>,
+) {
+    update_token_list(accounts, TOKEN_DENYLIST_SUFFIX, token_ids, false);
+}
+
+/// Insert or remove the tokens, the change in storage is paid from the storage reserve
+fn update_token_list<Info: AccountInfoTrait>(
+    accounts: &mut Accounts<Info>,
+    suffix: &[u8],
+    token_ids: Vec<AccountId>,
+    insert: bool,
+) {
+    assert_one_yocto();
+    accounts.assert_owner();
+    let token_list = new_token_list(accounts, suffix);
+    state::update(
+        accounts,
+        suffix,
+        || token_list,
+        |token_list| {
+            for token_id in token_ids.iter() {
+                if insert {
+                    token_list.insert(token_id);
+                } else {
+                    token_list.remove(token_id);
+                }
+            }
+        },
+    )
+    .unwrap_or_panic();
+}
+
+/// Check if deposits of the token are accepted under the current policy
+pub fn is_token_allowed<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    token_id: &AccountId,
+) -> bool {
+    match get_token_policy(accounts) {
+        TokenPolicy::AllowAll => true,
+        TokenPolicy::Allowlist => {
+            read_token_list(accounts, TOKEN_ALLOWLIST_SUFFIX).contains(token_id)
+        }
+        TokenPolicy::Denylist => {
+            !read_token_list(accounts, TOKEN_DENYLIST_SUFFIX).contains(token_id)
+        }
+    }
+}
+
+/// Check if the token is in the allowlist and allowed under the current policy
+pub fn is_token_explicitly_allowed<Info: AccountInfoTrait>(
+    accounts: &Accounts<Info>,
+    token_id: &AccountId,
+) -> bool {
+    read_token_list(accounts, TOKEN_ALLOWLIST_SUFFIX).contains(token_id)
+        && is_token_allowed(accounts, token_id)
+}
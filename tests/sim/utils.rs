@@ -10,10 +10,12 @@ use near_sdk_sim::{
 near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
     DUMMY_BYTES => "res/dummy.wasm",
     FT_BYTES => "res/ft.wasm",
+    DEFI_BYTES => "res/defi.wasm",
 }
 
 const DUMMY_ID: &str = "dummy";
 const FT_ID: &str = "ft";
+const DEFI_ID: &str = "defi";
 
 // Register the given `user` with FT contract
 pub fn register_user(user: &near_sdk_sim::UserAccount) {
@@ -88,3 +90,29 @@ pub fn init_with_macros(
 
     (root, dummy, ft, alice)
 }
+
+/// Deploy a receiver of `ft_transfer_call` which refunds the amount given in the message
+pub fn init_defi(root: &UserAccount) -> UserAccount {
+    let defi = root.deploy(&DEFI_BYTES, DEFI_ID.to_string(), to_yocto("10"));
+    defi.call(
+        DEFI_ID.to_string(),
+        "new",
+        &json!({ "fungible_token_account_id": FT_ID }).to_string().into_bytes(),
+        DEFAULT_GAS,
+        0,
+    )
+    .assert_success();
+    root.call(
+        FT_ID.to_string(),
+        "storage_deposit",
+        &json!({
+            "account_id": defi.valid_account_id()
+        })
+        .to_string()
+        .into_bytes(),
+        near_sdk_sim::DEFAULT_GAS / 2,
+        near_sdk::env::storage_byte_cost() * 125, // attached deposit
+    )
+    .assert_success();
+    defi
+}
@@ -1,12 +1,12 @@
 use std::convert::TryFrom;
 
 use near_contract_standards::storage_management::{StorageBalance, StorageBalanceBounds};
-use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::json_types::{ValidAccountId, U128, U64};
 use near_sdk::serde::{self, Deserialize, Serialize};
 use near_sdk::serde_json::json;
 use near_sdk_sim::{call, to_yocto, transaction::ExecutionStatus, view, DEFAULT_GAS};
 
-use crate::utils::{init_with_macros as init, register_user};
+use crate::utils::{init_defi, init_with_macros as init, register_user};
 
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -15,6 +15,14 @@ pub struct StorageBalanceTmp {
     pub available: U128,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountStorageUsageTmp {
+    pub storage_usage: U64,
+    pub near_used_for_storage: U128,
+    pub storage_cost: U128,
+}
+
 const DEFAULT_TOTAL_SUPPLY: u128 = 1_000_000_000_000;
 
 #[test]
@@ -24,7 +32,7 @@ fn simulate_simple_storage_test() {
         view!(dummy.accounts_storage_balance_of(alice.valid_account_id())).unwrap_json();
     let init_free = storage_bal.available.0;
 
-    call!(alice, dummy.write_message("AAAAA".to_string()), deposit = 1).assert_success();
+    call!(alice, dummy.write_message("AAAAA".to_string())).assert_success();
 
     let storage_bal: StorageBalanceTmp =
         view!(dummy.accounts_storage_balance_of(alice.valid_account_id())).unwrap_json();
@@ -35,7 +43,11 @@ fn simulate_simple_storage_test() {
 
     assert_eq!(init_free, post_free + 5 * near_sdk::env::storage_byte_cost());
 
-    call!(alice, dummy.write_message("".to_string()), deposit = 1).assert_success();
+    let storage_usage: AccountStorageUsageTmp =
+        view!(dummy.accounts_storage_usage_of(alice.valid_account_id())).unwrap_json();
+    assert_eq!(storage_usage.near_used_for_storage, storage_usage.storage_cost);
+
+    call!(alice, dummy.write_message("".to_string())).assert_success();
 
     let storage_bal: StorageBalanceTmp =
         view!(dummy.accounts_storage_balance_of(alice.valid_account_id())).unwrap_json();
@@ -122,3 +134,223 @@ fn simulate_simple_internal_balances_test_with_sender_id() {
     assert_eq!(ft_bal_alice_internal.0, 0);
 }
 // TODO: sim specificdeposit to
+
+#[test]
+fn simulate_withdraw_to_call_with_partial_refund() {
+    let (root, dummy, ft, _alice) = init(DEFAULT_TOTAL_SUPPLY);
+    let defi = init_defi(&root);
+    let amount_transfer = 1_000;
+    let amount_refunded = 400;
+
+    call!(
+        root,
+        ft.ft_transfer_call(dummy.valid_account_id(), amount_transfer.into(), None, "".to_string()),
+        deposit = 1
+    )
+    .assert_success();
+
+    // The receiver refunds the amount given in the message
+    call!(
+        root,
+        dummy.withdraw_to_call(
+            ft.valid_account_id(),
+            amount_transfer.into(),
+            defi.valid_account_id(),
+            amount_refunded.to_string()
+        ),
+        deposit = 1
+    )
+    .assert_success();
+
+    let ft_bal_root_internal: U128 =
+        view!(dummy.get_ft_balance(root.valid_account_id(), ft.valid_account_id())).unwrap_json();
+    assert_eq!(ft_bal_root_internal.0, amount_refunded);
+
+    let ft_bal_defi: U128 = view!(ft.ft_balance_of(defi.valid_account_id())).unwrap_json();
+    assert_eq!(ft_bal_defi.0, amount_transfer - amount_refunded);
+
+    let ft_bal_dummy: U128 = view!(ft.ft_balance_of(dummy.valid_account_id())).unwrap_json();
+    assert_eq!(ft_bal_dummy.0, amount_refunded);
+}
+
+#[test]
+fn simulate_withdraw_to_call_fully_used() {
+    let (root, dummy, ft, _alice) = init(DEFAULT_TOTAL_SUPPLY);
+    let defi = init_defi(&root);
+    let amount_transfer = 1_000;
+
+    call!(
+        root,
+        ft.ft_transfer_call(dummy.valid_account_id(), amount_transfer.into(), None, "".to_string()),
+        deposit = 1
+    )
+    .assert_success();
+
+    call!(
+        root,
+        dummy.withdraw_to_call(
+            ft.valid_account_id(),
+            amount_transfer.into(),
+            defi.valid_account_id(),
+            "take-my-money".to_string()
+        ),
+        deposit = 1
+    )
+    .assert_success();
+
+    let ft_bal_root_internal: U128 =
+        view!(dummy.get_ft_balance(root.valid_account_id(), ft.valid_account_id())).unwrap_json();
+    assert_eq!(ft_bal_root_internal.0, 0);
+
+    let ft_bal_defi: U128 = view!(ft.ft_balance_of(defi.valid_account_id())).unwrap_json();
+    assert_eq!(ft_bal_defi.0, amount_transfer);
+}
+
+#[test]
+fn simulate_withdraw_many_with_a_failing_withdrawal() {
+    let (root, dummy, ft, _alice) = init(DEFAULT_TOTAL_SUPPLY);
+    let defi = init_defi(&root);
+    let amount_transfer = 1_000;
+
+    call!(
+        root,
+        ft.ft_transfer_call(dummy.valid_account_id(), amount_transfer.into(), None, "".to_string()),
+        deposit = 1
+    )
+    .assert_success();
+
+    // The second withdrawal fails as the recipient is not registered with the token
+    call!(
+        root,
+        dummy.withdraw_many(vec![
+            near_internal_balances_plugin::WithdrawRequest {
+                token_id: ft.valid_account_id(),
+                amount: 700.into(),
+                recipient: Some(defi.valid_account_id()),
+                msg: Some("200".to_string()),
+            },
+            near_internal_balances_plugin::WithdrawRequest {
+                token_id: ft.valid_account_id(),
+                amount: 300.into(),
+                recipient: Some(ValidAccountId::try_from("nobody").unwrap()),
+                msg: None,
+            },
+        ]),
+        deposit = 1
+    )
+    .assert_success();
+
+    let ft_bal_root_internal: U128 =
+        view!(dummy.get_ft_balance(root.valid_account_id(), ft.valid_account_id())).unwrap_json();
+    assert_eq!(ft_bal_root_internal.0, 200 + 300);
+
+    let ft_bal_defi: U128 = view!(ft.ft_balance_of(defi.valid_account_id())).unwrap_json();
+    assert_eq!(ft_bal_defi.0, 500);
+}
+
+#[test]
+fn simulate_balance_transfer_call_with_partial_refund() {
+    let (root, dummy, ft, _alice) = init(DEFAULT_TOTAL_SUPPLY);
+    let amount_transfer = 1_000;
+    let amount_refunded = 400;
+
+    call!(
+        root,
+        ft.ft_transfer_call(dummy.valid_account_id(), amount_transfer.into(), None, "".to_string()),
+        deposit = 1
+    )
+    .assert_success();
+
+    // The dummy contract receives the balance itself, so it has to be registered
+    root.call(
+        dummy.account_id(),
+        "accounts_storage_deposit",
+        &json!({ "account_id": dummy.valid_account_id() }).to_string().into_bytes(),
+        DEFAULT_GAS / 2,
+        near_sdk::env::storage_byte_cost() * 1_000,
+    )
+    .assert_success();
+
+    // The receiver refunds the amount given in the message
+    call!(
+        root,
+        dummy.balance_transfer_call(
+            dummy.valid_account_id(),
+            ft.valid_account_id(),
+            amount_transfer.into(),
+            amount_refunded.to_string()
+        ),
+        deposit = 1
+    )
+    .assert_success();
+
+    let ft_bal_root_internal: U128 =
+        view!(dummy.get_ft_balance(root.valid_account_id(), ft.valid_account_id())).unwrap_json();
+    assert_eq!(ft_bal_root_internal.0, amount_refunded);
+
+    let ft_bal_dummy_internal: U128 =
+        view!(dummy.get_ft_balance(dummy.valid_account_id(), ft.valid_account_id())).unwrap_json();
+    assert_eq!(ft_bal_dummy_internal.0, amount_transfer - amount_refunded);
+}
+
+#[test]
+fn simulate_transfer_from_with_allowance() {
+    let (root, dummy, ft, alice) = init(DEFAULT_TOTAL_SUPPLY);
+    let amount_transfer = 1_000;
+
+    call!(
+        root,
+        ft.ft_transfer_call(dummy.valid_account_id(), amount_transfer.into(), None, "".to_string()),
+        deposit = 1
+    )
+    .assert_success();
+
+    call!(
+        root,
+        dummy.approve(alice.valid_account_id(), ft.valid_account_id(), 300.into()),
+        deposit = 1
+    )
+    .assert_success();
+
+    call!(
+        alice,
+        dummy.transfer_from(
+            root.valid_account_id(),
+            alice.valid_account_id(),
+            ft.valid_account_id(),
+            200.into()
+        ),
+        deposit = 1
+    )
+    .assert_success();
+
+    let allowance: U128 = view!(dummy.allowance(
+        root.valid_account_id(),
+        alice.valid_account_id(),
+        ft.valid_account_id()
+    ))
+    .unwrap_json();
+    assert_eq!(allowance.0, 100);
+
+    let ft_bal_alice_internal: U128 =
+        view!(dummy.get_ft_balance(alice.valid_account_id(), ft.valid_account_id())).unwrap_json();
+    assert_eq!(ft_bal_alice_internal.0, 200);
+
+    // Spending more than the remaining allowance fails
+    let res = call!(
+        alice,
+        dummy.transfer_from(
+            root.valid_account_id(),
+            alice.valid_account_id(),
+            ft.valid_account_id(),
+            200.into()
+        ),
+        deposit = 1
+    );
+    match res.status() {
+        ExecutionStatus::Failure(err) => {
+            assert!(err.to_string().contains("ERR_INSUFFICIENT_ALLOWANCE"))
+        }
+        _ => panic!("Expected the transfer to fail"),
+    }
+}